@@ -0,0 +1,3936 @@
+//! Detector binary of the three kornia-rs crates, each of which builds this
+//! file against its own pinned kornia-rs revision.
+
+use anyhow::{Context, Result};
+use clap::{CommandFactory, FromArgMatches};
+use cpu_time::ThreadTime;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use jpeg_encoder::{ColorType, Encoder};
+use kornia_apriltag::{AprilTagDecoder, DecodeTagsConfig};
+use kornia_apriltag::family::{TagFamily, TagFamilyKind};
+use kornia_apriltag::decoder::{QuickDecode, SharpeningBuffer};
+use kornia_detector_common::color::{shift_to_8bit, GrayMethod};
+use kornia_detector_common::draw::RgbCanvas;
+use kornia_detector_common::options::{parse_args, Args, Command, ConsensusArgs, Options, LOW_MARGIN};
+use kornia_detector_common::output::{
+    centre_origin, detection_uid, homography_from_corners, normalize_winding, polygon_area, quad_center, quad_winding, scene_centroid, sort_corners_ccw, sort_detections, BoundingBox,
+    CoordinateOrigin, Corner, Detection, DecoderConfig, DetectionCount, DetectionResult, ErrorLog, FamilyInfo, FamilyTiming, FlatDetection,
+    ImageError, ImageRobustness, ImageScore, ImageSummary, Manifest, OutputFormat, PartitionCounts, QualityResult,
+    RejectedDetection, RejectedResult, RepeatStats, Robustness, Scoring, Summary, Timings, SCHEMA_VERSION,
+};
+use kornia_image::{Image, ImageSize};
+use kornia_image::allocator::CpuAllocator;
+use kornia_imgproc::color::gray_from_rgb_u8;
+use kornia_imgproc::interpolation::InterpolationMode;
+use kornia_imgproc::warp::{get_rotation_matrix2d, warp_affine};
+use kornia_io::jpeg::read_image_jpeg_rgb8;
+use kornia_io::png::{read_image_png_mono16, read_image_png_mono8, read_image_png_rgb8, write_image_png_rgb8};
+use notify::event::{ModifyKind, RenameMode};
+use notify::{EventKind, RecursiveMode, Watcher};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rayon::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::io::{IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, info_span, warn};
+use tracing_subscriber::EnvFilter;
+use zune_jpeg::zune_core::colorspace::ColorSpace;
+use zune_jpeg::zune_core::options::DecoderOptions;
+use zune_jpeg::JpegDecoder;
+
+/// Name of this binary, recorded as `detector` in the outputs.
+const DETECTOR_NAME: &str = env!("CARGO_PKG_NAME");
+
+/// Peak resident set size of this process so far, in MiB.
+#[cfg(target_os = "linux")]
+fn peak_memory_mb() -> Option<f64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmHWM:"))?;
+    let kib: f64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib / 1024.0)
+}
+
+/// Peak resident set size of this process so far, in MiB.
+#[cfg(target_os = "macos")]
+fn peak_memory_mb() -> Option<f64> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    // SAFETY: getrusage fully initialises `usage` when it returns 0.
+    let usage = unsafe {
+        if libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) != 0 {
+            return None;
+        }
+        usage.assume_init()
+    };
+    // ru_maxrss is reported in bytes on macOS
+    Some(usage.ru_maxrss as f64 / (1024.0 * 1024.0))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn peak_memory_mb() -> Option<f64> {
+    None
+}
+
+fn tag_family_to_string(kind: &TagFamilyKind) -> String {
+    match kind {
+        TagFamilyKind::Tag16H5 => "tag16h5".to_string(),
+        TagFamilyKind::Tag36H11 => "tag36h11".to_string(),
+        TagFamilyKind::Tag36H10 => "tag36h10".to_string(),
+        TagFamilyKind::Tag25H9 => "tag25h9".to_string(),
+        TagFamilyKind::TagCircle21H7 => "tagCircle21h7".to_string(),
+        TagFamilyKind::TagCircle49H12 => "tagCircle49h12".to_string(),
+        TagFamilyKind::TagCustom48H12 => "tagCustom48h12".to_string(),
+        TagFamilyKind::TagStandard41H12 => "tagStandard41h12".to_string(),
+        TagFamilyKind::TagStandard52H13 => "tagStandard52h13".to_string(),
+        TagFamilyKind::Custom(family) => family.name.clone(),
+    }
+}
+
+fn get_supported_families() -> Vec<(String, TagFamilyKind)> {
+    vec![
+        ("tag36h11".to_string(), TagFamilyKind::Tag36H11),
+        ("tag36h10".to_string(), TagFamilyKind::Tag36H10),
+        ("tag25h9".to_string(), TagFamilyKind::Tag25H9),
+        ("tag16h5".to_string(), TagFamilyKind::Tag16H5),
+        ("tagCircle21h7".to_string(), TagFamilyKind::TagCircle21H7),
+        ("tagCircle49h12".to_string(), TagFamilyKind::TagCircle49H12),
+        ("tagCustom48h12".to_string(), TagFamilyKind::TagCustom48H12),
+        ("tagStandard41h12".to_string(), TagFamilyKind::TagStandard41H12),
+        ("tagStandard52h13".to_string(), TagFamilyKind::TagStandard52H13),
+    ]
+}
+
+/// A `--custom-family` definition, with the same fields as the families
+/// generated for the C AprilTag library.
+#[derive(Debug, Deserialize)]
+struct CustomFamilyDefinition {
+    width_at_border: usize,
+    total_width: usize,
+    #[serde(default)]
+    reversed_border: bool,
+    nbits: usize,
+    bit_x: Vec<i8>,
+    bit_y: Vec<i8>,
+    codes: Vec<usize>,
+    /// Minimum Hamming distance between any two codes.
+    min_hamming: u32,
+}
+
+/// Loads a `--custom-family` definition file into a family registered as
+/// `name`, returned with the file's `min_hamming`.
+fn load_custom_family(name: &str, path: &Path) -> Result<(TagFamilyKind, u32)> {
+    let definition: CustomFamilyDefinition =
+        read_json(path).context(format!("Failed to load custom family {} from {:?}", name, path))?;
+    if definition.codes.is_empty() {
+        anyhow::bail!("Custom family {} in {:?} has no codes", name, path);
+    }
+    if definition.nbits == 0 || definition.nbits > 64 {
+        anyhow::bail!("Custom family {} in {:?} must have between 1 and 64 bits", name, path);
+    }
+    if definition.bit_x.len() != definition.nbits || definition.bit_y.len() != definition.nbits {
+        anyhow::bail!("Custom family {} in {:?} needs one bit_x and bit_y entry per bit", name, path);
+    }
+    if definition.width_at_border == 0 || definition.total_width < definition.width_at_border {
+        anyhow::bail!("Custom family {} in {:?} has an invalid width", name, path);
+    }
+    if definition.min_hamming == 0 || definition.min_hamming as usize > definition.nbits {
+        anyhow::bail!("Custom family {} in {:?} must have a min_hamming between 1 and nbits", name, path);
+    }
+
+    let quick_decode = QuickDecode::new(definition.nbits, &definition.codes);
+    let family = TagFamily {
+        name: name.to_string(),
+        width_at_border: definition.width_at_border,
+        reversed_border: definition.reversed_border,
+        total_width: definition.total_width,
+        nbits: definition.nbits,
+        bit_x: definition.bit_x,
+        bit_y: definition.bit_y,
+        code_data: definition.codes,
+        quick_decode,
+        sharpening_buffer: SharpeningBuffer::new(definition.total_width * definition.total_width),
+    };
+    Ok((TagFamilyKind::Custom(family), definition.min_hamming))
+}
+
+/// Minimum Hamming distance of each `--custom-family`, by family name.
+type CustomMinHamming = HashMap<String, u32>;
+
+/// Adds the `--custom-family` definitions after the built-in families, also
+/// returning each custom family's minimum Hamming distance.
+fn add_custom_families(
+    mut families: Vec<(String, TagFamilyKind)>,
+    options: &Options,
+) -> Result<(Vec<(String, TagFamilyKind)>, CustomMinHamming)> {
+    let mut custom_min_hamming = HashMap::new();
+    for (name, path) in &options.custom_families {
+        if families.iter().any(|(family_name, _)| family_name == name) {
+            anyhow::bail!("--custom-family {} clashes with an existing family name", name);
+        }
+        let (kind, min_hamming) = load_custom_family(name, Path::new(path))?;
+        info!("Loaded custom family {} from {}", name, path);
+        families.push((name.clone(), kind));
+        custom_min_hamming.insert(name.clone(), min_hamming);
+    }
+    Ok((families, custom_min_hamming))
+}
+
+/// Minimum Hamming distance between the codes of a built-in family, as
+/// published with the AprilTag families. `None` for custom families.
+fn builtin_min_hamming(family_kind: &TagFamilyKind) -> Option<u32> {
+    match family_kind {
+        TagFamilyKind::Tag16H5 => Some(5),
+        TagFamilyKind::Tag25H9 => Some(9),
+        TagFamilyKind::Tag36H10 => Some(10),
+        TagFamilyKind::Tag36H11 => Some(11),
+        TagFamilyKind::TagCircle21H7 => Some(7),
+        TagFamilyKind::TagCircle49H12 => Some(12),
+        TagFamilyKind::TagCustom48H12 => Some(12),
+        TagFamilyKind::TagStandard41H12 => Some(12),
+        TagFamilyKind::TagStandard52H13 => Some(13),
+        TagFamilyKind::Custom(_) => None,
+    }
+}
+
+/// Describes a family's code from the decoder's own definition of it. The
+/// decoder does not keep the Hamming distance, so that comes from the built-in
+/// table or, for custom families, `custom_min_hamming`.
+fn family_info(
+    family_name: &str,
+    family_kind: &TagFamilyKind,
+    custom_min_hamming: &CustomMinHamming,
+    options: &Options,
+) -> Result<FamilyInfo> {
+    let config = decode_config(family_kind, options)?;
+    let family = config
+        .tag_families
+        .first()
+        .context(format!("No decoder definition for family {}", family_name))?;
+    let min_hamming = builtin_min_hamming(family_kind)
+        .or_else(|| custom_min_hamming.get(family_name).copied())
+        .context(format!("No minimum Hamming distance for family {}", family_name))?;
+    Ok(FamilyInfo {
+        name: family_name.to_string(),
+        bits: family.nbits,
+        min_hamming,
+        codes: family.code_data.len(),
+    })
+}
+
+/// Restricts `families` to the `--families` names, keeping the supported order.
+/// An empty list keeps every family.
+fn filter_families(
+    families: Vec<(String, TagFamilyKind)>,
+    names: &[String],
+) -> Result<Vec<(String, TagFamilyKind)>> {
+    if names.is_empty() {
+        return Ok(families);
+    }
+
+    for name in names {
+        if !families.iter().any(|(family_name, _)| family_name == name) {
+            let valid: Vec<&str> = families.iter().map(|(family_name, _)| family_name.as_str()).collect();
+            anyhow::bail!("Unknown family {} in --families, expected one of: {}", name, valid.join(", "));
+        }
+    }
+
+    Ok(families.into_iter().filter(|(family_name, _)| names.contains(family_name)).collect())
+}
+
+struct DetectionWithTiming {
+    detections: Vec<Detection>,
+    rejected: Vec<RejectedDetection>,
+    detection_count: usize,
+    /// Tags dropped for a low decision margin.
+    filtered_count: usize,
+    family_timing: FamilyTiming,
+}
+
+impl DetectionWithTiming {
+    /// Result of a family whose decode hit `--per-family-timeout`, or that was
+    /// never started because an earlier family of the image did.
+    fn timed_out(family_name: &str, scale: Option<f32>, initialization: Duration, waited: Duration) -> Self {
+        DetectionWithTiming {
+            detections: Vec::new(),
+            rejected: Vec::new(),
+            detection_count: 0,
+            filtered_count: 0,
+            family_timing: FamilyTiming {
+                family: family_name.to_string(),
+                initialization_ms: initialization.as_secs_f64() * 1000.0,
+                detection_ms: waited.as_secs_f64() * 1000.0,
+                detection_cpu_ms: 0.0,
+                repeat_stats: None,
+                scale,
+                timed_out: true,
+                detection_count: 0,
+            },
+        }
+    }
+}
+
+/// Decoders kept across images, keyed by family name, with the image size each
+/// was built for.
+type DecoderCache = HashMap<String, (ImageSize, AprilTagDecoder)>;
+
+/// Decoder configuration for one family with the command-line overrides applied.
+fn decode_config(family_kind: &TagFamilyKind, options: &Options) -> Result<DecodeTagsConfig> {
+    let mut config = DecodeTagsConfig::new(vec![family_kind.clone()])?;
+    if let Some(refine_edges) = options.refine_edges {
+        config.refine_edges_enabled = refine_edges;
+    }
+    if let Some(quad_decimate) = options.quad_decimate {
+        config.downscale_factor = quad_decimate;
+    }
+    if let Some(decode_sharpening) = options.decode_sharpening {
+        config.decode_sharpening = decode_sharpening;
+    }
+    Ok(config)
+}
+
+/// Detects one family in `img_gray`. When `scale` is given, the image is a
+/// downscaled copy and corners are mapped back to full-resolution coordinates.
+/// The family's cached decoder is reused if it was built for the same image
+/// size, and rebuilt otherwise. The image is shared with the decoding thread
+/// under `--per-family-timeout`.
+fn detect_in_image(
+    img_gray: &Arc<GrayImage>,
+    family_name: &str,
+    family_kind: &TagFamilyKind,
+    scale: Option<f32>,
+    options: &Options,
+    decoders: &mut DecoderCache,
+) -> Result<DetectionWithTiming> {
+    let filter = &options.filter;
+
+    let img_size = ImageSize {
+        width: img_gray.width(),
+        height: img_gray.height(),
+    };
+
+    // Time initialization, which is zero for a reused decoder
+    let init_start = Instant::now();
+    // A decoder's buffers are sized for one image size, so any other size needs a new one
+    let cached = decoders.remove(family_name).filter(|(size, _)| {
+        let same_size = size.width == img_size.width && size.height == img_size.height;
+        if !same_size {
+            debug!(
+                previous_width = size.width,
+                previous_height = size.height,
+                width = img_size.width,
+                height = img_size.height,
+                "Image size changed, rebuilding decoder"
+            );
+        }
+        same_size
+    });
+    let (mut decoder, init_duration) = match cached {
+        Some((_, mut decoder)) => {
+            decoder.clear();
+            (decoder, Duration::ZERO)
+        }
+        None => {
+            let config = decode_config(family_kind, options)?;
+            let decoder = AprilTagDecoder::new(config, img_size)?;
+            (decoder, init_start.elapsed())
+        }
+    };
+
+    // Time detection (wall-clock and CPU time of the decoding thread)
+    let decode = |mut decoder: AprilTagDecoder, img: &GrayImage| {
+        let detect_start = Instant::now();
+        let detect_cpu_start = ThreadTime::now();
+        let detections = decoder.decode(img).map_err(anyhow::Error::from);
+        (decoder, detections, detect_start.elapsed(), detect_cpu_start.elapsed())
+    };
+    let wait_start = Instant::now();
+    let decoded = match options.per_family_timeout {
+        None => Some(decode(decoder, img_gray)),
+        Some(timeout) => {
+            let (tx, rx) = mpsc::channel();
+            let img = Arc::clone(img_gray);
+            std::thread::spawn(move || {
+                // The receiver is gone if the timeout already fired
+                let _ = tx.send(decode(decoder, &img));
+            });
+            match rx.recv_timeout(timeout) {
+                Ok(decoded) => Some(decoded),
+                Err(mpsc::RecvTimeoutError::Timeout) => None,
+                Err(mpsc::RecvTimeoutError::Disconnected) => anyhow::bail!("Decoding thread panicked"),
+            }
+        }
+    };
+
+    // The abandoned decoder stays with its thread, so the next image builds a new one
+    let Some((mut decoder, detections, detect_duration, detect_cpu_duration)) = decoded else {
+        warn!(
+            "{} detection did not finish within {} ms, recording no detections",
+            family_name,
+            wait_start.elapsed().as_millis()
+        );
+        return Ok(DetectionWithTiming::timed_out(family_name, scale, init_duration, wait_start.elapsed()));
+    };
+    let detections = detections.context(format!("Failed to decode tags for family {:?}", family_kind))?;
+    let detection_ms = detect_duration.as_secs_f64() * 1000.0;
+
+    // Repeated runs reuse the decoder and only contribute timings
+    let repeat_stats = if options.repeat > 1 {
+        let mut samples_ms = vec![detection_ms];
+        for _ in 1..options.repeat {
+            decoder.clear();
+            let run_start = Instant::now();
+            decoder.decode(img_gray)
+                .context(format!("Failed to decode tags for family {:?}", family_kind))?;
+            samples_ms.push(run_start.elapsed().as_secs_f64() * 1000.0);
+        }
+        Some(RepeatStats::from_samples(&samples_ms))
+    } else {
+        None
+    };
+    decoders.insert(family_name.to_string(), (img_size, decoder));
+
+    let mut family_timing = FamilyTiming {
+        family: family_name.to_string(),
+        initialization_ms: init_duration.as_secs_f64() * 1000.0,
+        detection_ms,
+        detection_cpu_ms: detect_cpu_duration.as_secs_f64() * 1000.0,
+        repeat_stats,
+        scale,
+        timed_out: false,
+        detection_count: 0,
+    };
+
+    // Benchmarks only need the count, so skip building output structs
+    if options.bench_only {
+        let detection_count = detections
+            .iter()
+            .filter(|det| filter.rejection_reason(det.decision_margin, det.hamming).is_none())
+            .count();
+        let filtered_count = detections
+            .iter()
+            .filter(|det| filter.rejection_reason(det.decision_margin, det.hamming) == Some(LOW_MARGIN))
+            .count();
+        family_timing.detection_count = detection_count;
+        return Ok(DetectionWithTiming {
+            detections: Vec::new(),
+            rejected: Vec::new(),
+            detection_count,
+            filtered_count,
+            family_timing,
+        });
+    }
+
+    let to_corner = |x: f32, y: f32| match scale {
+        Some(scale) => unscale_corner(x, y, scale),
+        None => Corner { x, y },
+    };
+
+    // The decoder only returns refined corners, so an untimed second decode
+    // without refinement provides the positions to measure against
+    let unrefined: Vec<(u16, Vec<Corner>)> = if options.refine_edges == Some(true) {
+        let mut config = decode_config(family_kind, options)?;
+        config.refine_edges_enabled = false;
+        AprilTagDecoder::new(config, img_size)?
+            .decode(img_gray)
+            .context(format!("Failed to decode tags for family {:?}", family_kind))?
+            .iter()
+            .map(|det| (det.id, det.quad.corners.iter().map(|p| to_corner(p.x, p.y)).collect()))
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    // Convert detections to our format
+    let mut result_detections = Vec::new();
+    let mut rejected = Vec::new();
+    for det in detections {
+        let corners: Vec<Corner> = det.quad.corners.iter().map(|p| to_corner(p.x, p.y)).collect();
+        let tag_family = tag_family_to_string(&det.tag_family_kind);
+
+        if let Some(reason) = filter.rejection_reason(det.decision_margin, det.hamming) {
+            rejected.push(RejectedDetection {
+                tag_id: det.id,
+                tag_family,
+                corners,
+                decision_margin: det.decision_margin,
+                hamming: det.hamming,
+                reason: reason.to_string(),
+            });
+            continue;
+        }
+
+        let bbox = options.emit_bbox.then(|| BoundingBox::from_corners(&corners));
+        let refine_delta = unrefined
+            .iter()
+            .filter(|(id, _)| *id == det.id)
+            .map(|(_, unrefined_corners)| mean_corner_error(&corners, unrefined_corners) as f32)
+            .min_by(f32::total_cmp);
+        let distance_m = options.distance.map(|model| model.distance_m(&corners));
+        result_detections.push(Detection {
+            uid: detection_uid(&tag_family, det.id),
+            tag_id: det.id,
+            tag_family,
+            center: quad_center(&corners),
+            area: polygon_area(&corners),
+            aspect_ratio: quad_aspect_ratio(&corners),
+            winding: quad_winding(&corners).to_string(),
+            corners,
+            bbox,
+            distance_m,
+            scale,
+            refine_delta,
+            homography: None,
+            translation: None,
+            rotation: None,
+            decision_margin: det.decision_margin,
+            hamming: det.hamming,
+        });
+    }
+
+    family_timing.detection_count = result_detections.len();
+    Ok(DetectionWithTiming {
+        detection_count: result_detections.len(),
+        detections: result_detections,
+        filtered_count: rejected.iter().filter(|r| r.reason == LOW_MARGIN).count(),
+        rejected,
+        family_timing,
+    })
+}
+
+type GrayImage = Image<u8, 1, CpuAllocator>;
+
+/// Hex-encoded SHA-256 of a file's raw bytes, streamed rather than read whole.
+fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = fs::File::open(path).context(format!("Failed to open {:?}", path))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).context(format!("Failed to hash {:?}", path))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ImageFormat {
+    Png,
+    Jpeg,
+    WebP,
+    Tiff,
+}
+
+/// Identifies an image from its leading bytes, so it loads by content even if
+/// its extension names another format.
+fn sniff_image_format(path: &Path) -> Result<ImageFormat> {
+    const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+    const JPEG_SOI: [u8; 2] = [0xff, 0xd8];
+    const TIFF_LITTLE_ENDIAN: [u8; 4] = [b'I', b'I', 42, 0];
+    const TIFF_BIG_ENDIAN: [u8; 4] = [b'M', b'M', 0, 42];
+    // A RIFF container: "RIFF", the chunk size, then the form type
+    const WEBP_HEADER_LEN: u64 = 12;
+
+    let mut magic = Vec::with_capacity(WEBP_HEADER_LEN as usize);
+    fs::File::open(path)
+        .and_then(|file| file.take(WEBP_HEADER_LEN).read_to_end(&mut magic))
+        .context(format!("Failed to read {:?}", path))?;
+
+    if magic.starts_with(&PNG_SIGNATURE) {
+        Ok(ImageFormat::Png)
+    } else if magic.starts_with(&JPEG_SOI) {
+        Ok(ImageFormat::Jpeg)
+    } else if magic.starts_with(b"RIFF") && magic.get(8..12) == Some(b"WEBP".as_slice()) {
+        Ok(ImageFormat::WebP)
+    } else if magic.starts_with(&TIFF_LITTLE_ENDIAN) || magic.starts_with(&TIFF_BIG_ENDIAN) {
+        Ok(ImageFormat::Tiff)
+    } else {
+        anyhow::bail!("Not a PNG, JPEG, WebP or TIFF image: {:?}", path)
+    }
+}
+
+/// Returns `(bit_depth, color_type)` from the IHDR chunk of a PNG file.
+fn read_png_header(path: &Path) -> Result<(u8, u8)> {
+    // 8-byte signature, then the IHDR chunk: length, type, width, height, bit depth, color type
+    let mut header = [0u8; 26];
+    fs::File::open(path)
+        .and_then(|mut file| file.read_exact(&mut header))
+        .context(format!("Failed to read PNG header of {:?}", path))?;
+    if &header[12..16] != b"IHDR" {
+        anyhow::bail!("Not a valid PNG file: {:?}", path);
+    }
+    Ok((header[24], header[25]))
+}
+
+/// Returns the EXIF orientation (1 to 8) of a JPEG, or `None` if it has none.
+fn read_jpeg_orientation(path: &Path) -> Result<Option<u16>> {
+    // Metadata segments precede the image data and each is at most 64 KiB
+    let mut data = Vec::new();
+    fs::File::open(path)
+        .and_then(|file| file.take(1 << 17).read_to_end(&mut data))
+        .context(format!("Failed to read JPEG header of {:?}", path))?;
+
+    let mut pos = 2;
+    while let Some(&[0xFF, marker, len_hi, len_lo]) = data.get(pos..pos + 4) {
+        let len = usize::from(u16::from_be_bytes([len_hi, len_lo]));
+        // Start of scan: the image data follows, with no more metadata
+        if marker == 0xDA || len < 2 {
+            break;
+        }
+        let segment = data.get(pos + 4..pos + 2 + len).unwrap_or_default();
+        if marker == 0xE1 {
+            if let Some(tiff) = segment.strip_prefix(b"Exif\0\0") {
+                return Ok(exif_orientation(tiff));
+            }
+        }
+        pos += 2 + len;
+    }
+    Ok(None)
+}
+
+/// Returns the number of colour components in a JPEG's start-of-frame header.
+/// Earlier segments are skipped by their lengths, so large EXIF or ICC blocks
+/// in front of the frame header don't hide it.
+fn read_jpeg_components(path: &Path) -> Result<u8> {
+    let file = fs::File::open(path).context(format!("Failed to open {:?}", path))?;
+    let mut reader = std::io::BufReader::new(file);
+    reader
+        .seek_relative(2)
+        .context(format!("Failed to read JPEG header of {:?}", path))?;
+
+    let mut header = [0u8; 4];
+    while reader.read_exact(&mut header).is_ok() {
+        let [0xFF, marker, len_hi, len_lo] = header else {
+            break;
+        };
+        let len = u16::from_be_bytes([len_hi, len_lo]);
+        if marker == 0xDA || len < 2 {
+            break;
+        }
+        // SOF0 to SOF15, except the DHT, JPG and DAC markers sharing the range
+        if (0xC0..=0xCF).contains(&marker) && ![0xC4, 0xC8, 0xCC].contains(&marker) {
+            // Precision, height and width precede the component count
+            let mut frame = [0u8; 6];
+            reader
+                .read_exact(&mut frame)
+                .context(format!("Truncated JPEG frame header in {:?}", path))?;
+            return Ok(frame[5]);
+        }
+        reader
+            .seek_relative(i64::from(len) - 2)
+            .context(format!("Failed to read JPEG header of {:?}", path))?;
+    }
+    anyhow::bail!("No JPEG frame header found in {:?}", path)
+}
+
+/// Returns the number of channels stored in the image file, before the
+/// conversion to grayscale. Palette PNGs count as three.
+fn image_channels(path: &Path) -> Result<u8> {
+    let format = match sniff_image_format(path)? {
+        ImageFormat::Png => {
+            let (_, color_type) = read_png_header(path)?;
+            return Ok(match color_type {
+                0 => 1,
+                4 => 2,
+                6 => 4,
+                _ => 3,
+            });
+        }
+        ImageFormat::Jpeg => return read_jpeg_components(path),
+        ImageFormat::WebP => image::ImageFormat::WebP,
+        ImageFormat::Tiff => image::ImageFormat::Tiff,
+    };
+    let file = fs::File::open(path).context(format!("Failed to open {:?}", path))?;
+    let decoder = image::ImageReader::with_format(std::io::BufReader::new(file), format)
+        .into_decoder()
+        .context(format!("Failed to read the header of {:?}", path))?;
+    Ok(image::ImageDecoder::color_type(&decoder).channel_count())
+}
+
+/// Finds the Orientation tag in IFD0 of an EXIF TIFF block.
+fn exif_orientation(tiff: &[u8]) -> Option<u16> {
+    const ORIENTATION_TAG: u16 = 0x0112;
+
+    let big_endian = match tiff.get(0..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let read_u16 = |at: usize| {
+        let bytes = [*tiff.get(at)?, *tiff.get(at + 1)?];
+        Some(if big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    };
+    let read_u32 = |at: usize| {
+        let (first, second) = (u32::from(read_u16(at)?), u32::from(read_u16(at + 2)?));
+        Some(if big_endian { first << 16 | second } else { second << 16 | first })
+    };
+
+    let ifd = read_u32(4)? as usize;
+    (0..usize::from(read_u16(ifd)?))
+        .map(|i| ifd + 2 + i * 12)
+        .find(|&entry| read_u16(entry) == Some(ORIENTATION_TAG))
+        .and_then(|entry| read_u16(entry + 8))
+        .filter(|orientation| (1..=8).contains(orientation))
+}
+
+/// Turns an image stored with EXIF `orientation` upright, as a viewer would
+/// display it.
+fn apply_orientation(img: &GrayImage, orientation: u16) -> Result<GrayImage> {
+    let (width, height) = (img.width(), img.height());
+    let pixels = img.as_slice();
+    // Orientations 5 to 8 swap the axes
+    let (out_width, out_height) = if orientation >= 5 { (height, width) } else { (width, height) };
+
+    let mut oriented = Vec::with_capacity(pixels.len());
+    for y in 0..out_height {
+        for x in 0..out_width {
+            let (sx, sy) = match orientation {
+                2 => (width - 1 - x, y),
+                3 => (width - 1 - x, height - 1 - y),
+                4 => (x, height - 1 - y),
+                5 => (y, x),
+                6 => (y, height - 1 - x),
+                7 => (width - 1 - y, height - 1 - x),
+                8 => (width - 1 - y, x),
+                _ => (x, y),
+            };
+            oriented.push(pixels[sy * width + sx]);
+        }
+    }
+
+    let size = ImageSize {
+        width: out_width,
+        height: out_height,
+    };
+    Ok(GrayImage::new(size, oriented, CpuAllocator)?)
+}
+
+/// Loads a single-channel JPEG without colour conversion (`--grayscale-input`).
+fn load_jpeg_luma(image_path: &Path) -> Result<GrayImage> {
+    let encoded = fs::read(image_path).context(format!("Failed to read {:?}", image_path))?;
+    let options = DecoderOptions::default().jpeg_set_out_colorspace(ColorSpace::Luma);
+    let mut decoder = JpegDecoder::new_with_options(&encoded, options);
+    decoder
+        .decode_headers()
+        .map_err(|e| anyhow::anyhow!("Failed to load image: {:?}", e))?;
+
+    let colorspace = decoder.get_input_colorspace();
+    if colorspace != Some(ColorSpace::Luma) {
+        anyhow::bail!(
+            "--grayscale-input expects a single-channel image, but {:?} is {:?}",
+            image_path,
+            colorspace.unwrap_or(ColorSpace::Unknown)
+        );
+    }
+    let (width, height) = decoder.dimensions().context("JPEG has no dimensions")?;
+    let pixels = decoder
+        .decode()
+        .map_err(|e| anyhow::anyhow!("Failed to load image: {:?}", e))?;
+
+    Ok(GrayImage::new(ImageSize { width, height }, pixels, CpuAllocator)?)
+}
+
+/// Converts to gray with `method`. BT.601 goes through kornia's own
+/// conversion, so default results match runs from before `--gray-method`.
+fn gray_from_rgb(img_rgb: &Image<u8, 3, CpuAllocator>, method: GrayMethod) -> Result<GrayImage> {
+    if method != GrayMethod::Bt601 {
+        return Ok(GrayImage::new(img_rgb.size(), method.to_gray(img_rgb.as_slice()), CpuAllocator)?);
+    }
+    let mut img_gray = GrayImage::from_size_val(img_rgb.size(), 0, CpuAllocator)?;
+    gray_from_rgb_u8(img_rgb, &mut img_gray)?;
+    Ok(img_gray)
+}
+
+/// Loads a WebP, TIFF or 16-bit colour PNG, which kornia-io cannot read,
+/// through the `image` crate. Grayscale images are used as-is and anything
+/// else goes through the same `gray_method` conversion as a JPEG. 16-bit samples
+/// are shifted down by `bit_shift` first, while floating-point samples are
+/// taken to span 0 to 1.
+fn load_with_image_crate(
+    image_path: &Path,
+    format: image::ImageFormat,
+    bit_shift: u32,
+    grayscale_input: bool,
+    gray_method: GrayMethod,
+) -> Result<(GrayImage, Option<u32>)> {
+    let file = fs::File::open(image_path).context(format!("Failed to open {:?}", image_path))?;
+    let decoded = image::load(std::io::BufReader::new(file), format).context("Failed to load image")?;
+    let size = ImageSize {
+        width: decoded.width() as usize,
+        height: decoded.height() as usize,
+    };
+
+    match decoded {
+        image::DynamicImage::ImageLuma8(gray) => Ok((GrayImage::new(size, gray.into_raw(), CpuAllocator)?, None)),
+        image::DynamicImage::ImageLuma16(gray) => {
+            let img_gray = GrayImage::new(size, shift_to_8bit(gray.as_raw(), bit_shift), CpuAllocator)?;
+            Ok((img_gray, Some(bit_shift)))
+        }
+        other if grayscale_input => anyhow::bail!(
+            "--grayscale-input expects a single-channel image, but {:?} is {:?}",
+            image_path,
+            other.color()
+        ),
+        other @ (image::DynamicImage::ImageLumaA16(_)
+        | image::DynamicImage::ImageRgb16(_)
+        | image::DynamicImage::ImageRgba16(_)) => {
+            let pixels = shift_to_8bit(other.into_rgb16().as_raw(), bit_shift);
+            let img_rgb = Image::<u8, 3, CpuAllocator>::new(size, pixels, CpuAllocator)?;
+            Ok((gray_from_rgb(&img_rgb, gray_method)?, Some(bit_shift)))
+        }
+        other => {
+            let img_rgb = Image::<u8, 3, CpuAllocator>::new(size, other.into_rgb8().into_raw(), CpuAllocator)?;
+            Ok((gray_from_rgb(&img_rgb, gray_method)?, None))
+        }
+    }
+}
+
+/// Loads an image as 8-bit grayscale.
+///
+/// 16-bit PNGs and TIFFs are right-shifted by `bit_shift` bits
+/// (saturating at 255), and the applied shift is returned so it can be
+/// recorded. Colour images are converted with `gray_method`, or with
+/// `grayscale_input` rejected instead.
+fn load_grayscale(
+    image_path: &Path,
+    bit_shift: u32,
+    grayscale_input: bool,
+    gray_method: GrayMethod,
+) -> Result<(GrayImage, Option<u32>)> {
+    const PNG_COLOR_TYPE_GRAYSCALE: u8 = 0;
+
+    let img_rgb = match sniff_image_format(image_path)? {
+        ImageFormat::Png => {
+            let (bit_depth, color_type) = read_png_header(image_path)?;
+            if grayscale_input && color_type != PNG_COLOR_TYPE_GRAYSCALE {
+                anyhow::bail!(
+                    "--grayscale-input expects a single-channel image, but {:?} has PNG colour type {}",
+                    image_path,
+                    color_type
+                );
+            }
+            if bit_depth == 16 && color_type == PNG_COLOR_TYPE_GRAYSCALE {
+                let img16 = read_image_png_mono16(image_path)
+                    .context("Failed to load image")?;
+                let img_gray = GrayImage::new(img16.size(), shift_to_8bit(img16.as_slice(), bit_shift), CpuAllocator)?;
+                return Ok((img_gray, Some(bit_shift)));
+            }
+            if bit_depth == 16 {
+                return load_with_image_crate(
+                    image_path,
+                    image::ImageFormat::Png,
+                    bit_shift,
+                    grayscale_input,
+                    gray_method,
+                );
+            }
+            if bit_depth == 8 && color_type == PNG_COLOR_TYPE_GRAYSCALE {
+                let img_gray = read_image_png_mono8(image_path).context("Failed to load image")?;
+                return Ok((img_gray, None));
+            }
+            read_image_png_rgb8(image_path).context("Failed to load image")?
+        }
+        ImageFormat::Jpeg if grayscale_input => return Ok((load_jpeg_luma(image_path)?, None)),
+        ImageFormat::Jpeg => read_image_jpeg_rgb8(image_path).context("Failed to load image")?,
+        ImageFormat::WebP => {
+            return load_with_image_crate(image_path, image::ImageFormat::WebP, bit_shift, grayscale_input, gray_method);
+        }
+        ImageFormat::Tiff => {
+            return load_with_image_crate(image_path, image::ImageFormat::Tiff, bit_shift, grayscale_input, gray_method);
+        }
+    };
+
+    Ok((gray_from_rgb(&img_rgb, gray_method)?, None))
+}
+
+/// Delay before the first `--read-retries` retry, doubled for each later one.
+const READ_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Calls `load_grayscale`, retrying failed reads up to `--read-retries` times
+/// so a transient error on a network filesystem does not fail the image. A file
+/// that has gone missing is not retried.
+fn load_grayscale_with_retries(image_path: &Path, options: &Options) -> Result<(GrayImage, Option<u32>)> {
+    let mut attempt = 0;
+    loop {
+        match load_grayscale(image_path, options.bit_shift, options.grayscale_input, options.gray_method) {
+            Err(e) if attempt < options.read_retries && image_path.is_file() => {
+                let delay = READ_RETRY_DELAY * 2u32.pow(attempt.min(5));
+                attempt += 1;
+                warn!(
+                    "Failed to read {} ({:#}), retry {} of {} in {} ms",
+                    image_path.display(),
+                    e,
+                    attempt,
+                    options.read_retries,
+                    delay.as_millis()
+                );
+                std::thread::sleep(delay);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Loads an image for detection and, unless `--ignore-exif` is given, turns a
+/// JPEG upright according to its EXIF orientation so corners match the image
+/// as it is displayed.
+fn load_image(image_path: &Path, options: &Options) -> Result<(GrayImage, Option<u32>)> {
+    let (img_gray, bit_shift) = load_grayscale_with_retries(image_path, options)?;
+    if options.ignore_exif || sniff_image_format(image_path)? != ImageFormat::Jpeg {
+        return Ok((img_gray, bit_shift));
+    }
+    match read_jpeg_orientation(image_path)? {
+        Some(orientation) if orientation != 1 => {
+            debug!(orientation, "Applying EXIF orientation");
+            Ok((apply_orientation(&img_gray, orientation)?, bit_shift))
+        }
+        _ => Ok((img_gray, bit_shift)),
+    }
+}
+
+/// Rotates an image counter-clockwise by `degrees` about its centre. The output
+/// is sized to the rotated bounds so no part of the input is cropped; uncovered
+/// pixels are black. Multiples of 90 degrees map pixels exactly.
+fn rotate_image(img: &GrayImage, degrees: f32) -> Result<GrayImage> {
+    let (width, height) = (img.width() as f32, img.height() as f32);
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let rotated_size = ImageSize {
+        width: (width * cos.abs() + height * sin.abs()).round() as usize,
+        height: (width * sin.abs() + height * cos.abs()).round() as usize,
+    };
+
+    // Rotate about the input centre, then shift that centre onto the output centre
+    let mut m = get_rotation_matrix2d(((width - 1.0) / 2.0, (height - 1.0) / 2.0), degrees, 1.0);
+    m[2] += (rotated_size.width as f32 - width) / 2.0;
+    m[5] += (rotated_size.height as f32 - height) / 2.0;
+
+    let src = img.cast::<f32>()?;
+    let mut dst = Image::<f32, 1, _>::from_size_val(rotated_size, 0.0, CpuAllocator)?;
+    warp_affine(&src, &mut dst, &m, InterpolationMode::Nearest)
+        .context(format!("Failed to rotate image by {} degrees", degrees))?;
+
+    Ok(dst.map(|&v| v.round().clamp(0.0, 255.0) as u8)?)
+}
+
+/// Round-trips an image through an in-memory JPEG at `quality` (1-100).
+fn degrade_jpeg(img: &GrayImage, quality: u8) -> Result<GrayImage> {
+    let too_large = || format!("{}x{} image is too large for JPEG", img.width(), img.height());
+    let width = u16::try_from(img.width()).with_context(too_large)?;
+    let height = u16::try_from(img.height()).with_context(too_large)?;
+
+    let mut encoded = Vec::new();
+    Encoder::new(&mut encoded, quality)
+        .encode(img.as_slice(), width, height, ColorType::Luma)
+        .context(format!("Failed to encode image at JPEG quality {}", quality))?;
+
+    let options = DecoderOptions::default().jpeg_set_out_colorspace(ColorSpace::Luma);
+    let pixels = JpegDecoder::new_with_options(&encoded, options)
+        .decode()
+        .map_err(|e| anyhow::anyhow!("Failed to decode JPEG at quality {}: {:?}", quality, e))?;
+
+    Ok(GrayImage::new(img.size(), pixels, CpuAllocator)?)
+}
+
+/// Re-runs every family on copies of `img_gray`, the image `baseline` was
+/// detected in, degraded to each `--jpeg-quality-sweep` level.
+fn evaluate_jpeg_robustness(
+    img_gray: &GrayImage,
+    image: &str,
+    families: &[(String, TagFamilyKind)],
+    options: &Options,
+    baseline: &[Detection],
+    decoders: &mut DecoderCache,
+) -> Result<ImageRobustness> {
+    let baseline_uids: HashSet<&str> = baseline.iter().map(|d| d.uid.as_str()).collect();
+
+    let mut qualities = Vec::new();
+    for &quality in &options.jpeg_qualities {
+        let _span = info_span!("jpeg_quality", quality).entered();
+        let degraded = Arc::new(degrade_jpeg(img_gray, quality)?);
+
+        let mut uids = HashSet::new();
+        let mut detection_count = 0;
+        for (family_name, family_kind) in families {
+            let result = detect_in_image(&degraded, family_name, family_kind, None, options, decoders)?;
+            detection_count += result.detection_count;
+            uids.extend(result.detections.into_iter().map(|d| d.uid));
+        }
+
+        let retained = uids.iter().filter(|uid| baseline_uids.contains(uid.as_str())).count();
+        debug!(detection_count, retained, "Evaluated JPEG quality");
+        qualities.push(QualityResult {
+            quality,
+            detection_count,
+            retained,
+        });
+    }
+
+    Ok(ImageRobustness {
+        image: image.to_string(),
+        baseline_count: baseline_uids.len(),
+        qualities,
+    })
+}
+
+/// Scale factors to detect `img` at: the `--multi-scale` list, or the single
+/// factor bringing its longer side down to `--max-image-dimension`. Empty when
+/// the image should be used as-is.
+fn detection_scales(img: &GrayImage, options: &Options) -> Vec<f32> {
+    match options.max_image_dimension {
+        Some(max_dimension) => {
+            let longer = img.width().max(img.height());
+            if longer > max_dimension {
+                vec![max_dimension as f32 / longer as f32]
+            } else {
+                Vec::new()
+            }
+        }
+        None => options.scales.clone(),
+    }
+}
+
+/// Resizes an image by `scale` for multi-scale detection, keeping pixel
+/// centres aligned with [`unscale_corner`].
+fn scale_image(img: &GrayImage, scale: f32) -> Result<GrayImage> {
+    let scaled_size = ImageSize {
+        width: ((img.width() as f32 * scale).round() as usize).max(1),
+        height: ((img.height() as f32 * scale).round() as usize).max(1),
+    };
+
+    // Forward map of pixel centres: x' = scale * (x + 0.5) - 0.5
+    let offset = (scale - 1.0) / 2.0;
+    let m = [scale, 0.0, offset, 0.0, scale, offset];
+
+    let src = img.cast::<f32>()?;
+    let mut dst = Image::<f32, 1, _>::from_size_val(scaled_size, 0.0, CpuAllocator)?;
+    warp_affine(&src, &mut dst, &m, InterpolationMode::Bilinear)
+        .context(format!("Failed to scale image by {}", scale))?;
+
+    Ok(dst.map(|&v| v.round().clamp(0.0, 255.0) as u8)?)
+}
+
+/// Maps a corner found in an image scaled by `scale` back to full resolution.
+fn unscale_corner(x: f32, y: f32, scale: f32) -> Corner {
+    Corner {
+        x: (x + 0.5) / scale - 0.5,
+        y: (y + 0.5) / scale - 0.5,
+    }
+}
+
+/// Mean length of the longer pair of opposite quad edges divided by that of the
+/// shorter pair: 1 for a square seen head-on. 0 for degenerate quads.
+fn quad_aspect_ratio(corners: &[Corner]) -> f32 {
+    let [p0, p1, p2, p3] = corners else {
+        return 0.0;
+    };
+    let edge = |a: &Corner, b: &Corner| (b.x - a.x).hypot(b.y - a.y);
+    let first_pair = edge(p0, p1) + edge(p2, p3);
+    let second_pair = edge(p1, p2) + edge(p3, p0);
+    let (shorter, longer) = (first_pair.min(second_pair), first_pair.max(second_pair));
+    if shorter > 0.0 {
+        longer / shorter
+    } else {
+        0.0
+    }
+}
+
+/// Clips `subject` to the convex polygon `clip` (Sutherland-Hodgman), for
+/// either winding of `clip`.
+fn clip_polygon(subject: &[Corner], clip: &[Corner]) -> Vec<Corner> {
+    let winding: f32 = clip
+        .iter()
+        .zip(clip.iter().cycle().skip(1))
+        .map(|(a, b)| a.x * b.y - b.x * a.y)
+        .sum::<f32>()
+        .signum();
+
+    let mut output = subject.to_vec();
+    for (a, b) in clip.iter().zip(clip.iter().cycle().skip(1)) {
+        let input = std::mem::take(&mut output);
+        // Positive on the inside of edge a -> b
+        let side = |p: &Corner| winding * ((b.x - a.x) * (p.y - a.y) - (b.y - a.y) * (p.x - a.x));
+        for (p, q) in input.iter().zip(input.iter().cycle().skip(1)) {
+            let (side_p, side_q) = (side(p), side(q));
+            if side_p >= 0.0 {
+                output.push(p.clone());
+            }
+            if (side_p >= 0.0) != (side_q >= 0.0) {
+                let t = side_p / (side_p - side_q);
+                output.push(Corner {
+                    x: p.x + t * (q.x - p.x),
+                    y: p.y + t * (q.y - p.y),
+                });
+            }
+        }
+    }
+    output
+}
+
+/// Intersection over union of two convex quads.
+fn quad_iou(a: &[Corner], b: &[Corner]) -> f32 {
+    let intersection = polygon_area(&clip_polygon(a, b));
+    let union = polygon_area(a) + polygon_area(b) - intersection;
+    if union > 0.0 {
+        intersection / union
+    } else {
+        0.0
+    }
+}
+
+/// Splits off detections whose quad overlaps a higher-margin detection from
+/// another family with IoU above `min_iou` (`--dedupe`). Returns the kept and
+/// the dropped detections.
+fn dedupe_families(mut detections: Vec<Detection>, min_iou: f32) -> (Vec<Detection>, Vec<Detection>) {
+    detections.sort_by(|a, b| b.decision_margin.total_cmp(&a.decision_margin));
+
+    let mut kept: Vec<Detection> = Vec::new();
+    let mut dropped = Vec::new();
+    for detection in detections {
+        let duplicate = kept.iter().any(|k| {
+            k.tag_family != detection.tag_family && quad_iou(&k.corners, &detection.corners) > min_iou
+        });
+        if duplicate {
+            dropped.push(detection);
+        } else {
+            kept.push(detection);
+        }
+    }
+    (kept, dropped)
+}
+
+/// Keeps the highest-margin detection of each `tag_id` per family
+/// (`--unique-ids`). Returns the kept and the dropped detections.
+fn unique_ids(mut detections: Vec<Detection>) -> (Vec<Detection>, Vec<Detection>) {
+    detections.sort_by(|a, b| b.decision_margin.total_cmp(&a.decision_margin));
+
+    let mut seen = HashSet::new();
+    detections
+        .into_iter()
+        .partition(|detection| seen.insert((detection.tag_family.clone(), detection.tag_id)))
+}
+
+/// Merges detections from several scales. A detection is dropped when a
+/// detection of the same tag from a larger scale has its centre within half an
+/// edge length, so each tag keeps its finest-resolution corners.
+fn merge_scales(mut detections: Vec<Detection>) -> Vec<Detection> {
+    let mean_edge = |d: &Detection| {
+        let perimeter: f32 = d
+            .corners
+            .iter()
+            .zip(d.corners.iter().cycle().skip(1))
+            .map(|(a, b)| (b.x - a.x).hypot(b.y - a.y))
+            .sum();
+        perimeter / d.corners.len() as f32
+    };
+
+    detections.sort_by(|a, b| b.scale.unwrap_or(1.0).total_cmp(&a.scale.unwrap_or(1.0)));
+
+    let mut merged: Vec<Detection> = Vec::new();
+    for detection in detections {
+        let centre = detection.centre();
+        let overlaps = merged.iter().any(|kept| {
+            let kept_centre = kept.centre();
+            kept.tag_family == detection.tag_family
+                && kept.tag_id == detection.tag_id
+                && (centre.x - kept_centre.x).hypot(centre.y - kept_centre.y) < mean_edge(kept) / 2.0
+        });
+        if !overlaps {
+            merged.push(detection);
+        }
+    }
+    merged
+}
+
+struct ProcessedImage {
+    result: DetectionResult,
+    rejected: Vec<RejectedDetection>,
+    detection_count: usize,
+    /// The decoded image, kept for `--jpeg-quality-sweep` to degrade.
+    image: Option<Arc<GrayImage>>,
+}
+
+impl ProcessedImage {
+    /// Rounds every emitted pixel coordinate to `decimals` places (`--coord-precision`).
+    fn round_coordinates(&mut self, decimals: u32) {
+        let factor = 10f64.powi(decimals as i32);
+        let round = |v: &mut f32| *v = ((f64::from(*v) * factor).round() / factor) as f32;
+        let round_corner = |c: &mut Corner| {
+            round(&mut c.x);
+            round(&mut c.y);
+        };
+
+        for detection in &mut self.result.detections {
+            detection.corners.iter_mut().for_each(round_corner);
+            round_corner(&mut detection.center);
+            if let Some(bbox) = &mut detection.bbox {
+                round(&mut bbox.x);
+                round(&mut bbox.y);
+                round(&mut bbox.w);
+                round(&mut bbox.h);
+            }
+        }
+        if let Some(centroid) = &mut self.result.scene_centroid {
+            round_corner(centroid);
+        }
+        for rejected in &mut self.rejected {
+            rejected.corners.iter_mut().for_each(round_corner);
+        }
+    }
+}
+
+/// Detects every family in `image_path`. The image is loaded and converted to
+/// grayscale once, and that buffer (or its rotated and scaled copies) is shared
+/// by all families rather than decoded again per family.
+fn process_image(
+    image_path: &Path,
+    families: &[(String, TagFamilyKind)],
+    options: &Options,
+    decoders: &mut DecoderCache,
+) -> Result<Vec<ProcessedImage>> {
+    let processing_start = Instant::now();
+    let image_name = image_name(image_path, options)?;
+
+    // Hashed separately so image_load_ms stays comparable with and without it
+    let image_sha256 = options
+        .hash_inputs
+        .then(|| sha256_file(image_path))
+        .transpose()?;
+
+    // Time image loading
+    let load_start = Instant::now();
+    let (img_gray, bit_shift) = load_image(image_path, options)?;
+    let load_duration = load_start.elapsed();
+    // Only reported in the output, so an unreadable header doesn't fail an image that loaded
+    let channels = image_channels(image_path)
+        .map_err(|e| warn!("Could not read the channel count of {} ({:#})", image_path.display(), e))
+        .ok();
+    // Shared by every rotation, which each add their own detection time
+    let setup_duration = processing_start.elapsed();
+
+    // Set once a family of this image hits --per-family-timeout. Its decode keeps
+    // running on a detached thread, so the remaining families are not started
+    // rather than piling more threads onto it
+    let family_timed_out = AtomicBool::new(false);
+
+    let mut detect_all = |img: &Arc<GrayImage>, rotation_deg: Option<f32>| -> Result<ProcessedImage> {
+        let detect_all_start = Instant::now();
+        let mut all_detections = Vec::new();
+        let mut all_rejected = Vec::new();
+        let mut family_timings = Vec::new();
+        let mut total_initialization_ms = 0.0;
+        let mut total_detection_ms = 0.0;
+        let mut detection_count = 0;
+        let mut filtered_count = 0;
+
+        // Scale 1.0 reuses the input rather than resampling it
+        let scaled_images = detection_scales(img, options)
+            .into_iter()
+            .map(|scale| {
+                let scaled = if scale == 1.0 { Arc::clone(img) } else { Arc::new(scale_image(img, scale)?) };
+                Ok((Some(scale), scaled))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let inputs: Vec<(Option<f32>, Arc<GrayImage>)> = if scaled_images.is_empty() {
+            vec![(None, Arc::clone(img))]
+        } else {
+            scaled_images
+        };
+
+        let detect_family = |family_name: &str, family_kind: &TagFamilyKind, decoders: &mut DecoderCache| {
+            let _span = info_span!("family", family = %family_name).entered();
+
+            inputs
+                .iter()
+                .map(|(scale, input)| {
+                    let scale = *scale;
+                    if family_timed_out.load(Ordering::Relaxed) {
+                        warn!("{} not started because an earlier family timed out", family_name);
+                        return Ok(DetectionWithTiming::timed_out(family_name, scale, Duration::ZERO, Duration::ZERO));
+                    }
+                    debug!(scale, "Detecting tags");
+
+                    let result = detect_in_image(input, family_name, family_kind, scale, options, decoders)?;
+                    if result.family_timing.timed_out {
+                        family_timed_out.store(true, Ordering::Relaxed);
+                    }
+                    // Per-family lines would drown out the per-image ones at the default level
+                    debug!(
+                        detections = result.detection_count,
+                        initialization_ms = result.family_timing.initialization_ms,
+                        detection_ms = result.family_timing.detection_ms,
+                        scale,
+                        "Processed family"
+                    );
+                    Ok(result)
+                })
+                .collect::<Result<Vec<DetectionWithTiming>>>()
+        };
+
+        // Process all families for this image, collected in family order either way
+        let family_results: Vec<Result<Vec<DetectionWithTiming>>> = if options.family_parallel {
+            // Each family takes its own cached decoder to its thread and returns it after
+            let mut family_decoders: Vec<DecoderCache> = families
+                .iter()
+                .map(|(family_name, _)| decoders.remove_entry(family_name).into_iter().collect())
+                .collect();
+            let results = families
+                .par_iter()
+                .zip(family_decoders.par_iter_mut())
+                .map(|((family_name, family_kind), family_decoders)| {
+                    detect_family(family_name, family_kind, family_decoders)
+                })
+                .collect();
+            decoders.extend(family_decoders.into_iter().flatten());
+            results
+        } else {
+            families
+                .iter()
+                .map(|(family_name, family_kind)| detect_family(family_name, family_kind, decoders))
+                .collect()
+        };
+
+        for family_result in family_results {
+            for result in family_result? {
+                total_initialization_ms += result.family_timing.initialization_ms;
+                total_detection_ms += result.family_timing.detection_ms;
+                detection_count += result.detection_count;
+                filtered_count += result.filtered_count;
+                all_detections.extend(result.detections);
+                all_rejected.extend(result.rejected);
+                family_timings.push(result.family_timing);
+            }
+        }
+
+        // Bench-only counts cannot be merged since no detections are kept
+        if !options.scales.is_empty() && !options.bench_only {
+            all_detections = merge_scales(all_detections);
+            detection_count = all_detections.len();
+        }
+
+        if let Some(min_iou) = options.dedupe_iou {
+            let (kept, duplicates) = dedupe_families(all_detections, min_iou);
+            all_detections = kept;
+            detection_count = all_detections.len();
+            all_rejected.extend(duplicates.into_iter().map(|d| RejectedDetection {
+                tag_id: d.tag_id,
+                tag_family: d.tag_family,
+                corners: d.corners,
+                decision_margin: d.decision_margin,
+                hamming: d.hamming,
+                reason: "duplicate_family".to_string(),
+            }));
+        }
+
+        let mut duplicate_ids = 0;
+        if options.unique_ids {
+            let (kept, duplicates) = unique_ids(all_detections);
+            all_detections = kept;
+            detection_count = all_detections.len();
+            duplicate_ids = duplicates.len();
+            all_rejected.extend(duplicates.into_iter().map(|d| RejectedDetection {
+                tag_id: d.tag_id,
+                tag_family: d.tag_family,
+                corners: d.corners,
+                decision_margin: d.decision_margin,
+                hamming: d.hamming,
+                reason: "duplicate_id".to_string(),
+            }));
+        }
+
+        if options.normalize_winding {
+            for detection in &mut all_detections {
+                normalize_winding(&mut detection.corners);
+                detection.winding = quad_winding(&detection.corners).to_string();
+            }
+            for rejected in &mut all_rejected {
+                normalize_winding(&mut rejected.corners);
+            }
+        }
+
+        if options.sort_corners {
+            for detection in &mut all_detections {
+                sort_corners_ccw(&mut detection.corners);
+                detection.winding = quad_winding(&detection.corners).to_string();
+            }
+            for rejected in &mut all_rejected {
+                sort_corners_ccw(&mut rejected.corners);
+            }
+        }
+
+        // The tag frame follows the corners as written, so fit only after reordering them
+        if options.emit_homography || options.pose.is_some() {
+            for detection in &mut all_detections {
+                let fitted_homography = homography_from_corners(&detection.corners);
+                let pose = options.pose.zip(fitted_homography).and_then(|(model, h)| model.estimate(&h));
+                detection.homography = fitted_homography.filter(|_| options.emit_homography);
+                detection.translation = pose.map(|(_, translation)| translation);
+                detection.rotation = pose.map(|(rotation, _)| rotation);
+            }
+        }
+
+        sort_detections(&mut all_detections);
+
+        // Refined corners can land just outside the frame, but a box is for cropping
+        for bbox in all_detections.iter_mut().filter_map(|d| d.bbox.as_mut()) {
+            bbox.clamp_to(img.width() as f32, img.height() as f32);
+        }
+
+        if let Some(dir) = &options.draw {
+            let name = output_filename(&output_stem(image_path, options)?, rotation_deg);
+            write_drawing(Path::new(dir), &name, img, &all_detections)?;
+        }
+
+        // Drawing and every fit above need pixel coordinates, so this comes last
+        if options.coordinate_origin == CoordinateOrigin::Center {
+            let (width, height) = (img.width() as f32, img.height() as f32);
+            for detection in &mut all_detections {
+                detection.to_centre_origin(width, height);
+            }
+            for rejected in &mut all_rejected {
+                for corner in &mut rejected.corners {
+                    *corner = centre_origin(corner, width, height);
+                }
+            }
+        }
+
+        Ok(ProcessedImage {
+            result: DetectionResult {
+                schema_version: SCHEMA_VERSION,
+                detector: DETECTOR_NAME.to_string(),
+                image: image_name.clone(),
+                scene_centroid: scene_centroid(&all_detections),
+                decoded_tags: options
+                    .include_rejected
+                    .then(|| all_detections.len() + all_rejected.len()),
+                detections: all_detections,
+                timings: Timings {
+                    image_load_ms: load_duration.as_secs_f64() * 1000.0,
+                    total_initialization_ms,
+                    total_detection_ms,
+                    family_timings,
+                },
+                bit_shift,
+                rotation_deg,
+                image_width: Some(img.width()),
+                image_height: Some(img.height()),
+                image_channels: channels,
+                image_sha256: image_sha256.clone(),
+                filtered_count: (options.filter.min_decision_margin > 0.0).then_some(filtered_count),
+                duplicate_ids: options.unique_ids.then_some(duplicate_ids),
+                error: None,
+                coordinate_system: Some(options.coordinate_origin.name().to_string()),
+                processing_ms: Some((setup_duration + detect_all_start.elapsed()).as_secs_f64() * 1000.0),
+            },
+            rejected: all_rejected,
+            detection_count,
+            image: None,
+        })
+    };
+
+    let img_gray = Arc::new(img_gray);
+    if options.rotations.is_empty() {
+        let mut processed = detect_all(&img_gray, None)?;
+        processed.image = (!options.jpeg_qualities.is_empty()).then_some(img_gray);
+        return Ok(vec![processed]);
+    }
+
+    options
+        .rotations
+        .iter()
+        .map(|&deg| {
+            let _span = info_span!("rotation", deg).entered();
+            detect_all(&Arc::new(rotate_image(&img_gray, deg)?), Some(deg))
+        })
+        .collect()
+}
+
+/// Runs [`process_image`] on a worker thread, returning `None` if it does not
+/// finish within `timeout`. Decoding cannot be interrupted, so a timed-out
+/// worker is left detached and keeps running until it finishes or the process exits.
+/// The worker cannot borrow the run's decoder cache, so it builds its own decoders.
+fn process_image_with_timeout(
+    image_path: &Path,
+    families: &[(String, TagFamilyKind)],
+    options: &Options,
+    timeout: Duration,
+) -> Result<Option<Vec<ProcessedImage>>> {
+    let (tx, rx) = mpsc::channel();
+    let image_path = image_path.to_path_buf();
+    let families = families.to_vec();
+    let options = options.clone();
+    std::thread::spawn(move || {
+        // The receiver is gone if the timeout already fired
+        let _ = tx.send(process_image(&image_path, &families, &options, &mut DecoderCache::new()));
+    });
+
+    match rx.recv_timeout(timeout) {
+        Ok(result) => result.map(Some),
+        Err(mpsc::RecvTimeoutError::Timeout) => Ok(None),
+        Err(mpsc::RecvTimeoutError::Disconnected) => anyhow::bail!("Detection thread panicked"),
+    }
+}
+
+fn is_supported_image(path: &Path) -> bool {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_lowercase();
+
+    matches!(ext.as_str(), "jpg" | "jpeg" | "png" | "webp" | "tif" | "tiff")
+}
+
+/// Returns true if the `--input` value should be expanded as a glob pattern
+/// rather than treated as a directory.
+fn is_glob_pattern(input: &str) -> bool {
+    input.contains(['*', '?', '['])
+}
+
+fn collect_glob_paths(pattern: &str) -> Result<Vec<PathBuf>> {
+    let mut image_paths = Vec::new();
+    for entry in glob::glob(pattern).context(format!("Invalid input pattern: {}", pattern))? {
+        let path = entry?;
+        if path.is_file() && is_supported_image(&path) {
+            image_paths.push(path);
+        }
+    }
+    Ok(image_paths)
+}
+
+/// Reads the newline-separated paths of a `--file-list` file, or of stdin for
+/// `-`, in order and without checking that they exist. Blank lines are
+/// ignored, and relative paths are resolved against `input_dir` (which is empty
+/// without `--input`).
+fn read_file_list(file_list: &str, input_dir: &Path) -> Result<Vec<PathBuf>> {
+    let contents = if file_list == "-" {
+        let mut contents = String::new();
+        std::io::stdin()
+            .read_to_string(&mut contents)
+            .context("Failed to read the file list from stdin")?;
+        contents
+    } else {
+        fs::read_to_string(file_list).context(format!("Failed to read file list {:?}", file_list))?
+    };
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| input_dir.join(line))
+        .collect())
+}
+
+/// Whether the file name of `path` matches `--pattern`; always true without one.
+fn matches_pattern(path: &Path, pattern: Option<&glob::Pattern>) -> bool {
+    pattern.is_none_or(|pattern| {
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| pattern.matches(name))
+    })
+}
+
+/// Collects the images in `input_path`, and with `recursive` in its
+/// subdirectories too, skipping hidden directories.
+fn collect_directory_paths(input_path: &Path, recursive: bool) -> Result<Vec<PathBuf>> {
+    let mut image_paths = Vec::new();
+    let mut pending = vec![input_path.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir).context(format!("Failed to read {:?}", dir))? {
+            let entry = entry?;
+            let path = entry.path();
+
+            if path.is_file() && is_supported_image(&path) {
+                image_paths.push(path);
+            } else if path.is_file() {
+                debug!(path = %path.display(), "Skipping unsupported file");
+            } else if recursive && path.is_dir() {
+                if entry.file_name().to_string_lossy().starts_with('.') {
+                    debug!(path = %path.display(), "Skipping hidden directory");
+                } else {
+                    pending.push(path);
+                }
+            }
+        }
+    }
+    // read_dir order is filesystem-dependent
+    image_paths.sort();
+    Ok(image_paths)
+}
+
+/// Name recorded as `image` and matched against `--tasks`: the file name, or
+/// under `--recursive` the path relative to the input directory with `/`
+/// separators.
+fn image_name(image_path: &Path, options: &Options) -> Result<String> {
+    let relative = if options.recursive {
+        image_path
+            .strip_prefix(&options.input)
+            .context(format!("{:?} is not inside the input directory", image_path))?
+    } else {
+        Path::new(image_path.file_name().context("Invalid image filename")?)
+    };
+    let parts = relative
+        .iter()
+        .map(|part| part.to_str().context("Invalid image filename"))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(parts.join("/"))
+}
+
+/// [`image_name`] without its extension, used to name the image's outputs.
+/// With `--flatten-names` it is prefixed with the image's parent directory
+/// name, so `a/img.jpg` and `b/img.jpg` write `a_img` and `b_img`.
+fn output_stem(image_path: &Path, options: &Options) -> Result<String> {
+    let name = image_name(image_path, options)?;
+    let stem = Path::new(&name).with_extension("").to_string_lossy().into_owned();
+    let parent = image_path
+        .parent()
+        .and_then(|parent| parent.file_name())
+        .and_then(|parent| parent.to_str())
+        .filter(|_| options.flatten_names);
+    Ok(match parent {
+        Some(parent) => format!("{}_{}", parent, stem),
+        None => stem,
+    })
+}
+
+/// Creates the output directory and the family or partition subdirectories
+/// the run writes into.
+fn create_output_dirs(output_path: &Path, families: &[(String, TagFamilyKind)], options: &Options) -> Result<()> {
+    fs::create_dir_all(output_path)
+        .context("Failed to create output directory")?;
+
+    if options.split_by_family && !options.bench_only {
+        for (family_name, _) in families {
+            fs::create_dir_all(output_path.join(family_name))
+                .context(format!("Failed to create output directory for {}", family_name))?;
+        }
+    }
+
+    if options.partition_output {
+        for partition in ["detected", "empty"] {
+            fs::create_dir_all(output_path.join(partition))
+                .context(format!("Failed to create {} output directory", partition))?;
+        }
+    }
+    Ok(())
+}
+
+/// Prints each image that would be processed with its families, then the
+/// totals, for `--dry-run`. Nothing is loaded or written.
+fn print_dry_run(
+    image_paths: &[PathBuf],
+    families: &[(String, TagFamilyKind)],
+    tasks: &HashMap<String, Vec<(String, TagFamilyKind)>>,
+    options: &Options,
+    output_path: &Path,
+) -> Result<()> {
+    let mut skipped = 0;
+    for image_path in image_paths {
+        let image_families = image_families(image_path, families, tasks, options)?;
+        let names: Vec<&str> = image_families.iter().map(|(name, _)| name.as_str()).collect();
+        let stem = output_stem(image_path, options)?;
+        if options.skip_existing
+            && image_path.is_file()
+            && has_existing_output(image_path, &stem, families, options, output_path)?
+        {
+            skipped += 1;
+            println!("{} (existing output, skipped)", image_path.display());
+        } else {
+            println!("{}: {}", image_path.display(), names.join(", "));
+        }
+    }
+
+    println!("{} images would be processed", image_paths.len() - skipped);
+    if skipped > 0 {
+        println!("{} images would be skipped with existing output", skipped);
+    }
+    if let Some(k) = options.auto_family {
+        println!("Families would be narrowed by --auto-family after the first {} images", k);
+    }
+    Ok(())
+}
+
+/// Prints the run totals to stdout, outside the log so they also show under
+/// the progress bar and `--quiet`.
+fn print_summary(summary: &Summary) {
+    println!("Images: {}", summary.total_images);
+    println!(
+        "Detections: {} ({:.2} per image)",
+        summary.total_detections, summary.mean_detections_per_image
+    );
+    for (family, count) in &summary.family_detections {
+        println!("  {}: {}", family, count);
+    }
+    println!("Wall-clock time: {:.1} s", summary.wall_clock_ms / 1000.0);
+}
+
+/// Fails if two input images would write to the same output files, which
+/// happens when stems repeat across directories or extensions.
+fn check_output_collisions(image_paths: &[PathBuf], options: &Options) -> Result<()> {
+    let mut writers: HashMap<String, &PathBuf> = HashMap::new();
+    for image_path in image_paths {
+        let stem = output_stem(image_path, options)?;
+        if let Some(other) = writers.insert(stem.clone(), image_path) {
+            anyhow::bail!(
+                "{:?} and {:?} would both write the output {:?}; rename one{}",
+                other,
+                image_path,
+                stem,
+                if options.flatten_names { "" } else { " or use --flatten-names" }
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Writes log lines to stdout, clearing the progress bar around each one so
+/// the two do not interleave.
+struct LogWriter(Option<ProgressBar>);
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match &self.0 {
+            Some(progress) => progress.suspend(|| std::io::stdout().write(buf)),
+            None => std::io::stdout().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stdout().flush()
+    }
+}
+
+/// Sets up the log subscriber. An explicit `--log-level` takes precedence over
+/// `RUST_LOG`; with neither, info-level events are shown, or only warnings
+/// while the progress bar replaces the per-image lines or with `--quiet`.
+fn init_logging(log_level: Option<&str>, quiet: bool, progress: Option<&ProgressBar>) -> Result<()> {
+    let default_level = if quiet || progress.is_some() { "warn" } else { "info" };
+    let filter = match log_level {
+        Some(level) => EnvFilter::try_new(level).context(format!("Invalid --log-level: {}", level))?,
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default_level)),
+    };
+    let progress = progress.cloned();
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_target(false)
+        .with_ansi(std::io::stdout().is_terminal())
+        .with_writer(move || LogWriter(progress.clone()))
+        .init();
+    Ok(())
+}
+
+/// Progress bar for the batch, drawn on stdout when it is a terminal. Starts
+/// empty; its length is set once the images are collected. `--watch` has no
+/// known total, so it keeps the per-image log lines instead.
+fn progress_bar(options: &Options) -> Result<Option<ProgressBar>> {
+    if options.quiet || options.watch || !std::io::stdout().is_terminal() {
+        return Ok(None);
+    }
+    let progress = ProgressBar::with_draw_target(Some(0), ProgressDrawTarget::stdout());
+    progress.set_style(
+        ProgressStyle::with_template("{elapsed_precise} [{wide_bar}] {pos}/{len} images, ETA {eta}")
+            .context("Invalid progress bar template")?
+            .progress_chars("=> "),
+    );
+    Ok(Some(progress))
+}
+
+/// Serializes `value` to `<name>.json` in `dir`, or `<name>.json.gz` when compressing.
+fn write_json<T: Serialize>(dir: &Path, name: &str, value: &T, compress: bool) -> Result<()> {
+    let json = serde_json::to_string_pretty(value)?;
+
+    if compress {
+        let output_file = dir.join(format!("{}.json.gz", name));
+        write_atomically(&output_file, |tmp_file| {
+            let file = fs::File::create(tmp_file)
+                .context(format!("Failed to create {:?}", output_file))?;
+            let mut encoder = GzEncoder::new(file, Compression::default());
+            encoder
+                .write_all(json.as_bytes())
+                .context(format!("Failed to write {:?}", output_file))?;
+            encoder
+                .finish()
+                .context(format!("Failed to write {:?}", output_file))?;
+            Ok(())
+        })?;
+    } else {
+        let output_file = dir.join(format!("{}.json", name));
+        write_atomically(&output_file, |tmp_file| {
+            fs::write(tmp_file, json).context(format!("Failed to write {:?}", output_file))
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Has `write` create a hidden temporary file next to `path` and then renames
+/// it over `path`, so an interrupted run leaves either the previous file or
+/// the complete new one, never a truncated one.
+fn write_atomically(path: &Path, write: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+    let file_name = path
+        .file_name()
+        .context(format!("Output path {:?} has no file name", path))?;
+    let tmp_file = path.with_file_name(format!(".{}.tmp", file_name.to_string_lossy()));
+
+    if let Err(e) = write(&tmp_file) {
+        let _ = fs::remove_file(&tmp_file);
+        return Err(e);
+    }
+    fs::rename(&tmp_file, path).context(format!("Failed to move {:?} into place", path))
+}
+
+/// Columns of `--format csv`, followed by `rotation_deg` when rotating.
+const CSV_COLUMNS: [&str; 11] = ["image", "tag_family", "tag_id", "x0", "y0", "x1", "y1", "x2", "y2", "x3", "y3"];
+
+/// Rows of `--format csv` for one result: one per detection, or with
+/// `--csv-empty-rows` a single row with empty detection columns if there are none.
+fn csv_rows(result: &DetectionResult, options: &Options) -> Vec<Vec<String>> {
+    let row = |detection: Option<&Detection>| {
+        let mut record = vec![result.image.clone()];
+        match detection {
+            Some(d) => {
+                record.push(d.tag_family.clone());
+                record.push(d.tag_id.to_string());
+                record.extend(d.corners.iter().flat_map(|c| [c.x.to_string(), c.y.to_string()]));
+            }
+            None => record.resize(CSV_COLUMNS.len(), String::new()),
+        }
+        if !options.rotations.is_empty() {
+            record.push(result.rotation_deg.map(|deg| deg.to_string()).unwrap_or_default());
+        }
+        record
+    };
+
+    if result.detections.is_empty() && options.csv_empty_rows {
+        return vec![row(None)];
+    }
+    result.detections.iter().map(|d| row(Some(d))).collect()
+}
+
+/// Writes `detections.csv` into `dir`, quoting fields as needed.
+fn write_detections_csv(dir: &Path, rows: &[Vec<String>], with_rotation: bool) -> Result<()> {
+    let output_file = dir.join("detections.csv");
+    write_atomically(&output_file, |tmp_file| {
+        let mut writer = csv::Writer::from_path(tmp_file)
+            .context(format!("Failed to create {:?}", output_file))?;
+
+        let mut header = CSV_COLUMNS.to_vec();
+        if with_rotation {
+            header.push("rotation_deg");
+        }
+        writer
+            .write_record(&header)
+            .context(format!("Failed to write {:?}", output_file))?;
+        for row in rows {
+            writer
+                .write_record(row)
+                .context(format!("Failed to write {:?}", output_file))?;
+        }
+        writer.flush().context(format!("Failed to write {:?}", output_file))?;
+        Ok(())
+    })
+}
+
+/// Saves `img` with `detections` drawn on it as `<dir>/<name>.png` (`--draw`).
+fn write_drawing(dir: &Path, name: &str, img: &GrayImage, detections: &[Detection]) -> Result<()> {
+    let mut canvas = RgbCanvas::from_gray(img.width(), img.height(), img.as_slice());
+    for detection in detections {
+        canvas.draw_detection(detection);
+    }
+
+    let output_file = dir.join(format!("{}.png", name));
+    if let Some(parent) = output_file.parent() {
+        fs::create_dir_all(parent).context(format!("Failed to create {:?}", parent))?;
+    }
+    let rgb = Image::<u8, 3, CpuAllocator>::new(img.size(), canvas.pixels, CpuAllocator)?;
+    write_atomically(&output_file, |tmp_file| {
+        write_image_png_rgb8(tmp_file, &rgb).context(format!("Failed to write {:?}", output_file))
+    })
+}
+
+/// Writes `<stem>.json` (and optionally `<stem>.rejected.json`) into `dir`.
+fn write_image_outputs(
+    dir: &Path,
+    stem: &str,
+    result: &DetectionResult,
+    rejected: Option<&[RejectedDetection]>,
+    compress: bool,
+) -> Result<()> {
+    // Nested under --recursive
+    create_stem_parent(dir, stem)?;
+    write_json(dir, stem, result, compress)?;
+
+    if let Some(rejected) = rejected {
+        let rejected = RejectedResult {
+            image: result.image.clone(),
+            rejected: rejected.to_vec(),
+        };
+        write_json(dir, &format!("{}.rejected", stem), &rejected, compress)?;
+    }
+
+    Ok(())
+}
+
+/// Creates the subdirectory of `dir` that a nested `stem` is written into
+/// under `--recursive`.
+fn create_stem_parent(dir: &Path, stem: &str) -> Result<()> {
+    if let Some(parent) = Path::new(stem).parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(dir.join(parent)).context(format!("Failed to create {:?}", dir.join(parent)))?;
+    }
+    Ok(())
+}
+
+/// Serializes `value` to `<name>.yaml` in `dir` (`--format yaml`).
+fn write_yaml<T: Serialize>(dir: &Path, name: &str, value: &T) -> Result<()> {
+    let yaml = serde_yaml::to_string(value)?;
+    let output_file = dir.join(format!("{}.yaml", name));
+    write_atomically(&output_file, |tmp_file| {
+        fs::write(tmp_file, yaml).context(format!("Failed to write {:?}", output_file))
+    })
+}
+
+/// Offset between the reference library's pixel origin (the top-left corner of
+/// the top-left pixel) and ours (the centre of the top-left pixel).
+const REFERENCE_PIXEL_OFFSET: f32 = 0.5;
+
+/// A detection as dumped from the reference apriltag C library's
+/// `apriltag_detection_t`. Other fields such as `hamming` or the centre `c` are ignored.
+#[derive(Debug, Deserialize)]
+struct ReferenceDetection {
+    id: u16,
+    family: String,
+    p: [[f32; 2]; 4],
+}
+
+impl ReferenceDetection {
+    /// Converts to the harness conventions: pixel centres at integer
+    /// coordinates and corners wound counter-clockwise as seen on screen.
+    fn into_detection(self) -> Detection {
+        let mut corners: Vec<Corner> = self
+            .p
+            .iter()
+            .map(|&[x, y]| Corner {
+                x: x - REFERENCE_PIXEL_OFFSET,
+                y: y - REFERENCE_PIXEL_OFFSET,
+            })
+            .collect();
+
+        // Reference corners may be either winding; match the documented order
+        normalize_winding(&mut corners);
+
+        Detection {
+            uid: detection_uid(&self.family, self.id),
+            tag_id: self.id,
+            tag_family: self.family,
+            center: quad_center(&corners),
+            area: polygon_area(&corners),
+            aspect_ratio: quad_aspect_ratio(&corners),
+            winding: quad_winding(&corners).to_string(),
+            corners,
+            bbox: None,
+            distance_m: None,
+            scale: None,
+            refine_delta: None,
+            homography: None,
+            translation: None,
+            rotation: None,
+            decision_margin: 0.0,
+            hamming: 0,
+        }
+    }
+}
+
+/// Contents of a `--ground-truth` file, in our own output format or as a
+/// reference library dump (a bare array or wrapped in `detections`).
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum GroundTruthFile {
+    Harness { detections: Vec<Detection> },
+    Reference(Vec<ReferenceDetection>),
+    WrappedReference { detections: Vec<ReferenceDetection> },
+}
+
+/// Loads the expected detections for an image from `<dir>/<image-stem>.json`,
+/// or returns `None` if there is no such file.
+fn load_ground_truth(dir: &Path, stem: &str, sort_corners: bool) -> Result<Option<Vec<Detection>>> {
+    let path = dir.join(format!("{}.json", stem));
+    if !path.is_file() {
+        return Ok(None);
+    }
+
+    let file: GroundTruthFile = read_json(&path)
+        .context(format!("Failed to parse ground truth {:?}", path))?;
+    let mut detections = match file {
+        GroundTruthFile::Harness { detections } => detections,
+        GroundTruthFile::Reference(detections)
+        | GroundTruthFile::WrappedReference { detections } => detections
+            .into_iter()
+            .map(ReferenceDetection::into_detection)
+            .collect(),
+    };
+
+    // Compare like with like when our own corners are reordered
+    if sort_corners {
+        for detection in &mut detections {
+            sort_corners_ccw(&mut detection.corners);
+        }
+    }
+    Ok(Some(detections))
+}
+
+/// Mean distance in pixels between corresponding corners of two quads.
+fn mean_corner_error(a: &[Corner], b: &[Corner]) -> f64 {
+    let total: f64 = a
+        .iter()
+        .zip(b)
+        .map(|(p, q)| f64::from(p.x - q.x).hypot(f64::from(p.y - q.y)))
+        .sum();
+    total / a.len().min(b.len()) as f64
+}
+
+/// Overlap a detection needs with an expected tag of the same family and id
+/// to count as finding it.
+const GROUND_TRUTH_MIN_IOU: f32 = 0.5;
+
+/// Matches detections to the expected ones by family and tag id, pairing
+/// duplicates by smallest corner error. Pairs overlapping less than
+/// [`GROUND_TRUTH_MIN_IOU`] are not matched.
+fn score_detections(image: &str, detections: &[Detection], expected: &[Detection]) -> ImageScore {
+    let mut unmatched: Vec<&Detection> = detections.iter().collect();
+    let mut matched_uids = Vec::new();
+    let mut missed = Vec::new();
+    let mut errors = Vec::new();
+
+    for truth in expected {
+        let best = unmatched
+            .iter()
+            .enumerate()
+            .filter(|(_, d)| d.tag_family == truth.tag_family && d.tag_id == truth.tag_id)
+            .filter(|(_, d)| quad_iou(&d.corners, &truth.corners) >= GROUND_TRUTH_MIN_IOU)
+            .map(|(i, d)| (i, mean_corner_error(&d.corners, &truth.corners)))
+            .min_by(|a, b| a.1.total_cmp(&b.1));
+        let uid = detection_uid(&truth.tag_family, truth.tag_id);
+        match best {
+            Some((i, error)) => {
+                unmatched.swap_remove(i);
+                errors.push(error);
+                matched_uids.push(uid);
+            }
+            None => missed.push(uid),
+        }
+    }
+
+    ImageScore {
+        image: image.to_string(),
+        matched: errors.len(),
+        matched_uids,
+        missed,
+        false_positives: unmatched
+            .iter()
+            .map(|d| detection_uid(&d.tag_family, d.tag_id))
+            .collect(),
+        mean_corner_error_px: (!errors.is_empty())
+            .then(|| errors.iter().sum::<f64>() / errors.len() as f64),
+    }
+}
+
+/// Base name (without extension) of the result file for an image, or for one
+/// rotated copy of it.
+fn output_filename(stem: &str, rotation_deg: Option<f32>) -> String {
+    match rotation_deg {
+        Some(deg) => format!("{}_rot{}", stem, deg),
+        None => stem.to_string(),
+    }
+}
+
+/// Returns true if every result file this run would write for an image
+/// already exists (`--skip-existing`). With `--hash-inputs`, each file must
+/// also record the current hash of the image.
+fn has_existing_output(
+    image_path: &Path,
+    stem: &str,
+    families: &[(String, TagFamilyKind)],
+    options: &Options,
+    output_path: &Path,
+) -> Result<bool> {
+    let extension = if options.compress { "json.gz" } else { "json" };
+    // Each file must exist in every family directory, but in only one partition
+    let dir_groups: Vec<Vec<PathBuf>> = if options.split_by_family {
+        families.iter().map(|(name, _)| vec![output_path.join(name)]).collect()
+    } else if options.partition_output {
+        vec![vec![output_path.join("detected"), output_path.join("empty")]]
+    } else {
+        vec![vec![output_path.to_path_buf()]]
+    };
+    let rotations: Vec<Option<f32>> = if options.rotations.is_empty() {
+        vec![None]
+    } else {
+        options.rotations.iter().copied().map(Some).collect()
+    };
+
+    let image_sha256 = options.hash_inputs.then(|| sha256_file(image_path)).transpose()?;
+    for dirs in &dir_groups {
+        for &rotation_deg in &rotations {
+            let name = format!("{}.{}", output_filename(stem, rotation_deg), extension);
+            let Some(path) = dirs.iter().map(|dir| dir.join(&name)).find(|path| path.is_file()) else {
+                return Ok(false);
+            };
+            // An unreadable file is treated as stale rather than failing the run
+            let Ok(existing) = read_json::<DetectionResult>(&path) else {
+                return Ok(false);
+            };
+            if existing.error.is_some() {
+                debug!(path = %path.display(), "Retrying image that failed before");
+                return Ok(false);
+            }
+            if let Some(image_sha256) = &image_sha256 {
+                if existing.image_sha256.as_ref() != Some(image_sha256) {
+                    debug!(path = %path.display(), "Input changed since output was written");
+                    return Ok(false);
+                }
+            }
+        }
+    }
+    Ok(true)
+}
+
+/// Header of the `--benchmark-csv` file.
+const BENCHMARK_CSV_HEADER: [&str; 5] = ["image", "detector", "family", "detections", "detection_ms"];
+
+/// Opens the `--benchmark-csv` file for appending, writing the header if it is new.
+fn open_benchmark_csv(path: &Path) -> Result<csv::Writer<fs::File>> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .context(format!("Failed to open benchmark CSV {:?}", path))?;
+    let is_new = file.metadata()?.len() == 0;
+    let mut writer = csv::Writer::from_writer(file);
+    if is_new {
+        writer
+            .write_record(BENCHMARK_CSV_HEADER)
+            .context("Failed to write benchmark CSV")?;
+    }
+    Ok(writer)
+}
+
+/// Per-image records accumulated over a run for the run-level output files.
+#[derive(Default)]
+struct RunRecords {
+    image_summaries: Vec<ImageSummary>,
+    image_scores: Vec<ImageScore>,
+    missing_ground_truth: Vec<String>,
+    image_errors: Vec<ImageError>,
+    image_robustness: Vec<ImageRobustness>,
+    flat_detections: Vec<FlatDetection>,
+    detection_counts: Vec<DetectionCount>,
+    csv_rows: Vec<Vec<String>>,
+    combined_results: Vec<DetectionResult>,
+    /// Advanced once per recorded image of the initial batch.
+    progress: Option<ProgressBar>,
+    total_detections: usize,
+    skipped_images: usize,
+    failed_images: usize,
+    /// Detections dropped by `--unique-ids`.
+    duplicate_ids: usize,
+    /// Detections per family, used by `--auto-family`.
+    family_detections: HashMap<String, usize>,
+    partition_counts: PartitionCounts,
+    benchmark_csv: Option<csv::Writer<fs::File>>,
+    ndjson: Option<fs::File>,
+}
+
+/// Families to run on `image_path`: its `--tasks` entry if it has one, otherwise `families`.
+fn image_families<'a>(
+    image_path: &Path,
+    families: &'a [(String, TagFamilyKind)],
+    tasks: &'a HashMap<String, Vec<(String, TagFamilyKind)>>,
+    options: &Options,
+) -> Result<&'a [(String, TagFamilyKind)]> {
+    let name = image_name(image_path, options)?;
+    Ok(tasks.get(&name).map_or(families, |f| f.as_slice()))
+}
+
+/// What the detection stage produced for one image, before anything is recorded.
+enum ImageOutcome {
+    Skipped,
+    TimedOut(Duration),
+    /// Loading or detection failed, with the error message.
+    Failed(String),
+    Processed(Vec<ProcessedImage>),
+}
+
+/// Runs detection on one image and writes its outputs, or records its summary
+/// entry in bench-only mode.
+fn handle_image(
+    image_path: &Path,
+    families: &[(String, TagFamilyKind)],
+    tasks: &HashMap<String, Vec<(String, TagFamilyKind)>>,
+    options: &Options,
+    output_path: &Path,
+    records: &mut RunRecords,
+    decoders: &mut DecoderCache,
+) -> Result<()> {
+    let outcome = detect_image(image_path, families, tasks, options, output_path, decoders)?;
+    record_image(image_path, outcome, families, tasks, options, output_path, records, decoders)?;
+    if let Some(progress) = &records.progress {
+        progress.inc(1);
+    }
+    Ok(())
+}
+
+/// The detection stage of [`handle_image`]. It only reads shared state, so it
+/// can run on worker threads.
+fn detect_image(
+    image_path: &Path,
+    families: &[(String, TagFamilyKind)],
+    tasks: &HashMap<String, Vec<(String, TagFamilyKind)>>,
+    options: &Options,
+    output_path: &Path,
+    decoders: &mut DecoderCache,
+) -> Result<ImageOutcome> {
+    let _span = info_span!("image", path = %image_path.display()).entered();
+    let image_families = image_families(image_path, families, tasks, options)?;
+
+    // Only a --file-list can name a missing file; it is recorded like any unreadable image
+    if !image_path.is_file() {
+        return Ok(ImageOutcome::Failed(format!("Image not found: {}", image_path.display())));
+    }
+
+    let output_stem = output_stem(image_path, options)?;
+    if options.skip_existing && has_existing_output(image_path, &output_stem, families, options, output_path)? {
+        debug!("Output already exists, skipping");
+        return Ok(ImageOutcome::Skipped);
+    }
+
+    let outcome = match options.per_image_timeout {
+        Some(timeout) => process_image_with_timeout(image_path, image_families, options, timeout)
+            .map(|processed| processed.map_or(ImageOutcome::TimedOut(timeout), ImageOutcome::Processed)),
+        None => process_image(image_path, image_families, options, decoders).map(ImageOutcome::Processed),
+    };
+
+    // A corrupt image is recorded rather than ending the batch
+    Ok(outcome.unwrap_or_else(|e| ImageOutcome::Failed(format!("{:#}", e))))
+}
+
+/// The recording stage of [`handle_image`]: scores, writes and accumulates the
+/// results of [`detect_image`] on the calling thread.
+#[allow(clippy::too_many_arguments)]
+fn record_image(
+    image_path: &Path,
+    outcome: ImageOutcome,
+    families: &[(String, TagFamilyKind)],
+    tasks: &HashMap<String, Vec<(String, TagFamilyKind)>>,
+    options: &Options,
+    output_path: &Path,
+    records: &mut RunRecords,
+    decoders: &mut DecoderCache,
+) -> Result<()> {
+    let _span = info_span!("image", path = %image_path.display()).entered();
+    let image_families = image_families(image_path, families, tasks, options)?;
+    let output_stem = output_stem(image_path, options)?;
+
+    let processed_images = match outcome {
+        ImageOutcome::Skipped => {
+            records.skipped_images += 1;
+            return Ok(());
+        }
+        ImageOutcome::TimedOut(timeout) => {
+            error!("Detection timed out after {} ms, skipping", timeout.as_millis());
+            let message = format!("Detection did not finish within {} ms", timeout.as_millis());
+            records.failed_images += 1;
+            records.image_errors.push(ImageError {
+                image: image_name(image_path, options)?,
+                reason: "timeout".to_string(),
+                message: message.clone(),
+            });
+            if !options.bench_only {
+                write_failed_result(image_path, &output_stem, message, families, options, output_path, records)?;
+            }
+            return Ok(());
+        }
+        ImageOutcome::Failed(message) => {
+            error!("Failed to process image: {}", message);
+            records.failed_images += 1;
+            records.image_errors.push(ImageError {
+                image: image_name(image_path, options)?,
+                reason: "failed".to_string(),
+                message: message.clone(),
+            });
+            if !options.bench_only {
+                write_failed_result(image_path, &output_stem, message, families, options, output_path, records)?;
+            }
+            return Ok(());
+        }
+        ImageOutcome::Processed(processed_images) => processed_images,
+    };
+
+    let ground_truth = match &options.ground_truth {
+        Some(dir) => {
+            let expected = load_ground_truth(Path::new(dir), &output_stem, options.sort_corners)?;
+            if expected.is_none() {
+                warn!("No ground truth for {}", image_path.display());
+                records.missing_ground_truth.push(image_name(image_path, options)?);
+            }
+            expected
+        }
+        None => None,
+    };
+
+    for mut processed in processed_images {
+        if let Some(decimals) = options.coord_precision {
+            processed.round_coordinates(decimals);
+        }
+        let result = processed.result;
+        records.total_detections += processed.detection_count;
+        records.duplicate_ids += result.duplicate_ids.unwrap_or(0);
+
+        // Counted after scale merging and --dedupe, like detection_count. Bench-only
+        // runs keep no detections, but cannot merge or dedupe either
+        let mut family_detections = BTreeMap::new();
+        if options.bench_only {
+            for timing in &result.timings.family_timings {
+                *family_detections.entry(timing.family.clone()).or_default() += timing.detection_count;
+            }
+        } else {
+            for detection in &result.detections {
+                *family_detections.entry(detection.tag_family.clone()).or_default() += 1;
+            }
+        }
+        for (family, count) in &family_detections {
+            *records.family_detections.entry(family.clone()).or_default() += count;
+        }
+
+        // Ground truth describes the unrotated image
+        if let (Some(expected), None) = (&ground_truth, result.rotation_deg) {
+            records
+                .image_scores
+                .push(score_detections(&result.image, &result.detections, expected));
+        }
+
+        if let Some(img_gray) = &processed.image {
+            records.image_robustness.push(evaluate_jpeg_robustness(
+                img_gray,
+                &result.image,
+                image_families,
+                options,
+                &result.detections,
+                decoders,
+            )?);
+        }
+
+        if let Some(csv) = &mut records.benchmark_csv {
+            for timing in &result.timings.family_timings {
+                csv.write_record([
+                    result.image.as_str(),
+                    DETECTOR_NAME,
+                    timing.family.as_str(),
+                    &timing.detection_count.to_string(),
+                    &timing.detection_ms.to_string(),
+                ])
+                .context("Failed to write benchmark CSV")?;
+            }
+            // Flushed per image so an interrupted run keeps the rows written so far
+            csv.flush().context("Failed to write benchmark CSV")?;
+        }
+
+        records.image_summaries.push(ImageSummary {
+            image: result.image.clone(),
+            rotation_deg: result.rotation_deg,
+            detection_count: processed.detection_count,
+            family_detections,
+            filtered_count: result.filtered_count,
+            timings: result.timings.clone(),
+        });
+        if options.bench_only {
+            info!("Benchmarked {}: {} detections", result.image, processed.detection_count);
+            continue;
+        }
+
+        if options.counts_only {
+            records.detection_counts.extend(image_families.iter().map(|(family, _)| DetectionCount {
+                image: result.image.clone(),
+                rotation_deg: result.rotation_deg,
+                family: family.clone(),
+                count: result.detections.iter().filter(|d| &d.tag_family == family).count(),
+            }));
+            continue;
+        }
+
+        if options.combined {
+            records.combined_results.push(result.clone());
+        }
+
+        // One line per result, written whole so a partial run stays parseable
+        if let Some(ndjson) = &mut records.ndjson {
+            let mut line = serde_json::to_string(&result)?;
+            line.push('\n');
+            ndjson
+                .write_all(line.as_bytes())
+                .and_then(|()| ndjson.flush())
+                .context("Failed to write NDJSON output")?;
+            continue;
+        }
+
+        if options.format == OutputFormat::Csv {
+            records.csv_rows.extend(csv_rows(&result, options));
+            continue;
+        }
+
+        if options.format == OutputFormat::FlatJson {
+            records.flat_detections.extend(
+                result
+                    .detections
+                    .iter()
+                    .map(|d| FlatDetection::new(&result.image, result.rotation_deg, d)),
+            );
+            continue;
+        }
+
+        info!("Writing results for {}: {} detections", result.image, result.detections.len());
+
+        // Write output JSON, one file per rotation when rotating
+        let output_filename = output_filename(&output_stem, result.rotation_deg);
+
+        if options.split_by_family {
+            for (family_name, _) in families {
+                let family_rejected: Vec<RejectedDetection> = processed
+                    .rejected
+                    .iter()
+                    .filter(|r| &r.tag_family == family_name)
+                    .cloned()
+                    .collect();
+                let mut family_result = result.for_family(family_name);
+                family_result.decoded_tags = result
+                    .decoded_tags
+                    .map(|_| family_result.detections.len() + family_rejected.len());
+                family_result.filtered_count = result
+                    .filtered_count
+                    .map(|_| family_rejected.iter().filter(|r| r.reason == LOW_MARGIN).count());
+                family_result.duplicate_ids = result
+                    .duplicate_ids
+                    .map(|_| family_rejected.iter().filter(|r| r.reason == "duplicate_id").count());
+                write_image_outputs(
+                    &output_path.join(family_name),
+                    &output_filename,
+                    &family_result,
+                    options.include_rejected.then_some(family_rejected.as_slice()),
+                    options.compress,
+                )?;
+            }
+        } else if options.partition_output {
+            let partition = if result.detections.is_empty() {
+                records.partition_counts.empty += 1;
+                "empty"
+            } else {
+                records.partition_counts.detected += 1;
+                "detected"
+            };
+            write_image_outputs(
+                &output_path.join(partition),
+                &output_filename,
+                &result,
+                options.include_rejected.then_some(processed.rejected.as_slice()),
+                options.compress,
+            )?;
+        } else if options.format == OutputFormat::Yaml {
+            create_stem_parent(output_path, &output_filename)?;
+            write_yaml(output_path, &output_filename, &result)?;
+        } else {
+            write_image_outputs(
+                output_path,
+                &output_filename,
+                &result,
+                options.include_rejected.then_some(processed.rejected.as_slice()),
+                options.compress,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes a result with `error` set and no detections for an image that could
+/// not be processed, so the output still has one result per image. Flat
+/// formats only list it in `errors.json`.
+fn write_failed_result(
+    image_path: &Path,
+    output_stem: &str,
+    message: String,
+    families: &[(String, TagFamilyKind)],
+    options: &Options,
+    output_path: &Path,
+    records: &mut RunRecords,
+) -> Result<()> {
+    let result = DetectionResult {
+        schema_version: SCHEMA_VERSION,
+        detector: DETECTOR_NAME.to_string(),
+        image: image_name(image_path, options)?,
+        detections: Vec::new(),
+        timings: Timings {
+            image_load_ms: 0.0,
+            total_initialization_ms: 0.0,
+            total_detection_ms: 0.0,
+            family_timings: Vec::new(),
+        },
+        bit_shift: None,
+        rotation_deg: None,
+        image_width: None,
+        image_height: None,
+        image_channels: None,
+        scene_centroid: None,
+        decoded_tags: None,
+        image_sha256: None,
+        filtered_count: None,
+        duplicate_ids: None,
+        error: Some(message),
+        coordinate_system: Some(options.coordinate_origin.name().to_string()),
+        processing_ms: None,
+    };
+
+    if let Some(ndjson) = &mut records.ndjson {
+        let mut line = serde_json::to_string(&result)?;
+        line.push('\n');
+        return ndjson
+            .write_all(line.as_bytes())
+            .and_then(|()| ndjson.flush())
+            .context("Failed to write NDJSON output");
+    }
+    if !matches!(options.format, OutputFormat::Json | OutputFormat::Yaml) || options.counts_only {
+        return Ok(());
+    }
+    if options.combined {
+        records.combined_results.push(result.clone());
+    }
+
+    if options.split_by_family {
+        for (family_name, _) in families {
+            write_image_outputs(
+                &output_path.join(family_name),
+                output_stem,
+                &result.for_family(family_name),
+                None,
+                options.compress,
+            )?;
+        }
+        Ok(())
+    } else if options.partition_output {
+        records.partition_counts.empty += 1;
+        write_image_outputs(&output_path.join("empty"), output_stem, &result, None, options.compress)
+    } else if options.format == OutputFormat::Yaml {
+        create_stem_parent(output_path, output_stem)?;
+        write_yaml(output_path, output_stem, &result)
+    } else {
+        write_image_outputs(output_path, output_stem, &result, None, options.compress)
+    }
+}
+
+/// How long a watched file's size must stay unchanged before it is read.
+const WATCH_SETTLE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Waits until `path`'s size stops changing so partially written files are not
+/// read. Returns false if the file disappears or shutdown is requested first.
+fn wait_for_stable_size(path: &Path, running: &AtomicBool) -> bool {
+    let mut last_size = None;
+    while running.load(Ordering::SeqCst) {
+        let Ok(metadata) = fs::metadata(path) else {
+            return false;
+        };
+        let size = metadata.len();
+        if size > 0 && last_size == Some(size) {
+            return true;
+        }
+        last_size = Some(size);
+        std::thread::sleep(WATCH_SETTLE_INTERVAL);
+    }
+    false
+}
+
+/// Runs [`handle_image`] over `image_paths` with detection spread across
+/// `options.jobs` worker threads, each keeping its own decoder cache. Results
+/// are recorded on this thread in input order, so outputs match a serial run.
+fn handle_images_parallel(
+    image_paths: &[PathBuf],
+    families: &[(String, TagFamilyKind)],
+    tasks: &HashMap<String, Vec<(String, TagFamilyKind)>>,
+    options: &Options,
+    output_path: &Path,
+    records: &mut RunRecords,
+    decoders: &mut DecoderCache,
+) -> Result<()> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(options.jobs)
+        .build()
+        .context("Failed to create worker thread pool")?;
+    let cancelled = AtomicBool::new(false);
+    let (tx, rx) = mpsc::channel();
+
+    std::thread::scope(|scope| {
+        let cancelled = &cancelled;
+        let worker = scope.spawn(move || {
+            pool.install(|| {
+                image_paths.par_iter().enumerate().for_each_init(
+                    || (tx.clone(), DecoderCache::new()),
+                    |(tx, worker_decoders), (index, image_path)| {
+                        if cancelled.load(Ordering::Relaxed) {
+                            return;
+                        }
+                        let outcome =
+                            detect_image(image_path, families, tasks, options, output_path, worker_decoders);
+                        // The receiver is gone if recording already failed
+                        let _ = tx.send((index, outcome));
+                    },
+                );
+            });
+        });
+
+        // Results arrive in completion order; hold them until their turn
+        let mut pending = BTreeMap::new();
+        let mut next = 0;
+        while next < image_paths.len() {
+            let Ok((index, outcome)) = rx.recv() else {
+                let _ = worker.join();
+                anyhow::bail!("Detection worker panicked");
+            };
+            pending.insert(index, outcome);
+
+            while let Some(outcome) = pending.remove(&next) {
+                let image_path = &image_paths[next];
+                let recorded = outcome.and_then(|outcome| {
+                    record_image(image_path, outcome, families, tasks, options, output_path, records, decoders)
+                });
+                if let Err(e) = recorded {
+                    cancelled.store(true, Ordering::Relaxed);
+                    return Err(e);
+                }
+                if let Some(progress) = &records.progress {
+                    progress.inc(1);
+                }
+                next += 1;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Watches `input_path` for new images and processes each one as it lands,
+/// until Ctrl-C. Images in `seen` were already processed and are skipped.
+/// Returns the number of images processed while watching.
+#[allow(clippy::too_many_arguments)]
+fn watch_input(
+    input_path: &Path,
+    mut seen: HashSet<PathBuf>,
+    families: &[(String, TagFamilyKind)],
+    tasks: &HashMap<String, Vec<(String, TagFamilyKind)>>,
+    options: &Options,
+    pattern: Option<&glob::Pattern>,
+    output_path: &Path,
+    records: &mut RunRecords,
+    decoders: &mut DecoderCache,
+) -> Result<usize> {
+    let running = Arc::new(AtomicBool::new(true));
+    let handler_flag = Arc::clone(&running);
+    ctrlc::set_handler(move || handler_flag.store(false, Ordering::SeqCst))
+        .context("Failed to install Ctrl-C handler")?;
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx).context("Failed to create file watcher")?;
+    watcher
+        .watch(input_path, RecursiveMode::NonRecursive)
+        .context(format!("Failed to watch {}", input_path.display()))?;
+    info!("Watching {} for new images (Ctrl-C to stop)", input_path.display());
+
+    // Pick up images that landed while the initial batch was being processed
+    let mut pending: Vec<PathBuf> = collect_directory_paths(input_path, false)?;
+
+    let mut processed_count = 0;
+    while running.load(Ordering::SeqCst) {
+        for path in pending.drain(..) {
+            if !is_supported_image(&path) || !matches_pattern(&path, pattern) || seen.contains(&path) {
+                continue;
+            }
+            if !wait_for_stable_size(&path, &running) {
+                continue;
+            }
+            seen.insert(path.clone());
+
+            // A bad frame should not stop a live capture run
+            match handle_image(&path, families, tasks, options, output_path, records, decoders) {
+                Ok(()) => processed_count += 1,
+                Err(e) => error!("Failed to process {}: {:#}", path.display(), e),
+            }
+        }
+
+        match rx.recv_timeout(WATCH_SETTLE_INTERVAL) {
+            // Files renamed into the directory arrive as a modify event
+            Ok(Ok(event)) => {
+                if matches!(
+                    event.kind,
+                    EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(RenameMode::To))
+                ) {
+                    pending.extend(event.paths);
+                }
+            }
+            Ok(Err(e)) => error!("Watch error: {}", e),
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    info!("Stopped watching {}", input_path.display());
+    Ok(processed_count)
+}
+
+/// Parses a `.json` or `.json.gz` file written by `write_json`.
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    let file = fs::File::open(path).context(format!("Failed to open {:?}", path))?;
+    let value = if path.extension().is_some_and(|e| e == "gz") {
+        serde_json::from_reader(GzDecoder::new(file))
+    } else {
+        serde_json::from_reader(std::io::BufReader::new(file))
+    };
+    Ok(value?)
+}
+
+/// Parses an output file: `.yaml` from `--format yaml`, otherwise as [`read_json`].
+fn read_output<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    if path.extension().is_some_and(|e| e == "yaml") {
+        let file = fs::File::open(path).context(format!("Failed to open {:?}", path))?;
+        return Ok(serde_yaml::from_reader(std::io::BufReader::new(file))?);
+    }
+    read_json(path)
+}
+
+/// Strips the extension of an output file name, or `None` if it is not one.
+fn output_file_stem(name: &str) -> Option<&str> {
+    [".json", ".json.gz", ".yaml"]
+        .into_iter()
+        .find_map(|extension| name.strip_suffix(extension))
+}
+
+/// Checks every output file in `dir` (and any family subdirectories listed in
+/// the manifest) against the harness structs. Returns one message per problem.
+fn verify_output_dir(dir: &Path) -> Result<Vec<String>> {
+    let mut problems = Vec::new();
+
+    // --format yaml writes the manifest as manifest.yaml
+    let manifest_name = if dir.join("manifest.yaml").is_file() { "manifest.yaml" } else { "manifest.json" };
+    let manifest = match read_output::<Manifest>(&dir.join(manifest_name)) {
+        Ok(manifest) => Some(manifest),
+        Err(e) => {
+            problems.push(format!("{}: {:#}", manifest_name, e));
+            None
+        }
+    };
+
+    let known_families: HashSet<String> = manifest
+        .iter()
+        .flat_map(|m| m.supported_families.iter().cloned())
+        .collect();
+    let mut scan_dirs = vec![(dir.to_path_buf(), None)];
+
+    if let Some(manifest) = &manifest {
+        if known_families.len() != manifest.supported_families.len() {
+            problems.push(format!("{}: supported_families contains duplicates", manifest_name));
+        }
+        for family in manifest.family_directories.iter().flatten() {
+            if !known_families.contains(family) {
+                problems.push(format!(
+                    "{}: family directory {} is not a supported family",
+                    manifest_name, family
+                ));
+            }
+            let family_dir = dir.join(family);
+            if family_dir.is_dir() {
+                scan_dirs.push((family_dir, Some(family.as_str())));
+            } else {
+                problems.push(format!("{}: family directory {} does not exist", manifest_name, family));
+            }
+        }
+        if manifest.partition_counts.is_some() {
+            for partition in ["detected", "empty"] {
+                scan_dirs.push((dir.join(partition), None));
+            }
+        }
+    }
+
+    // Nested results are only expected under --recursive, and never inside
+    // another scan directory
+    let recursive = manifest.as_ref().is_some_and(|m| m.recursive);
+    let scan_roots: Vec<PathBuf> = scan_dirs.iter().map(|(scan_dir, _)| scan_dir.clone()).collect();
+
+    let mut checked = 0;
+    for (scan_dir, dir_family) in scan_dirs {
+        let mut paths = Vec::new();
+        let mut pending = vec![scan_dir.clone()];
+        while let Some(current) = pending.pop() {
+            for entry in fs::read_dir(&current).context(format!("Failed to read {:?}", current))? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    if recursive && !scan_roots.contains(&path) {
+                        pending.push(path);
+                    }
+                } else {
+                    paths.push(path);
+                }
+            }
+        }
+        paths.sort();
+
+        for path in paths {
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            let Some(stem) = output_file_stem(name) else {
+                continue;
+            };
+            let run_level = dir_family.is_none() && path.parent() == Some(scan_dir.as_path());
+            if run_level && stem == "manifest" {
+                continue;
+            }
+            checked += 1;
+
+            // Collect the families each file mentions so they can be checked below
+            let flat_json = manifest
+                .as_ref()
+                .is_some_and(|m| m.format.as_deref() == Some(OutputFormat::FlatJson.name()));
+            let combined = manifest.as_ref().is_some_and(|m| m.combined);
+            let counts_only = manifest.as_ref().is_some_and(|m| m.counts_only);
+            let parsed = if run_level && flat_json && stem == "detections" {
+                read_output::<Vec<FlatDetection>>(&path)
+                    .map(|rows| rows.into_iter().map(|r| r.family).collect())
+            } else if run_level && counts_only && stem == "counts" {
+                read_output::<Vec<DetectionCount>>(&path)
+                    .map(|rows| rows.into_iter().map(|r| r.family).collect())
+            } else if run_level && stem == "scoring" {
+                read_output::<Scoring>(&path).map(|scoring| {
+                    scoring
+                        .images
+                        .into_iter()
+                        .flat_map(|i| i.false_positives)
+                        .filter_map(|uid| uid.split_once(':').map(|(family, _)| family.to_string()))
+                        .collect()
+                })
+            } else if run_level && combined && stem == "results" {
+                read_output::<Vec<DetectionResult>>(&path).map(|results| {
+                    results
+                        .into_iter()
+                        .flat_map(|r| r.detections)
+                        .map(|d| d.tag_family)
+                        .collect()
+                })
+            } else if run_level && stem == "robustness" {
+                read_output::<Robustness>(&path).map(|_| HashSet::new())
+            } else if run_level && stem == "errors" {
+                read_output::<ErrorLog>(&path).map(|_| HashSet::new())
+            } else if run_level && stem == "summary" {
+                read_output::<Summary>(&path).map(|summary| {
+                    summary
+                        .images
+                        .into_iter()
+                        .flat_map(|i| i.timings.family_timings)
+                        .map(|t| t.family)
+                        .collect::<HashSet<_>>()
+                })
+            } else if stem.ends_with(".rejected") {
+                read_output::<RejectedResult>(&path)
+                    .map(|rejected| rejected.rejected.into_iter().map(|r| r.tag_family).collect())
+            } else {
+                read_output::<DetectionResult>(&path).map(|result| {
+                    result
+                        .detections
+                        .into_iter()
+                        .map(|d| d.tag_family)
+                        .chain(result.timings.family_timings.into_iter().map(|t| t.family))
+                        .collect()
+                })
+            };
+            let families = match parsed {
+                Ok(families) => families,
+                Err(e) => {
+                    problems.push(format!("{}: {:#}", path.display(), e));
+                    continue;
+                }
+            };
+
+            for family in families {
+                if manifest.is_some() && !known_families.contains(&family) {
+                    problems.push(format!(
+                        "{}: family {} is not listed in the manifest",
+                        path.display(),
+                        family
+                    ));
+                }
+                if dir_family.is_some_and(|f| f != family) {
+                    problems.push(format!(
+                        "{}: family {} does not belong in this family directory",
+                        path.display(),
+                        family
+                    ));
+                }
+            }
+        }
+    }
+
+    info!("Verified {} files in {}", checked, dir.display());
+    Ok(problems)
+}
+
+/// Entry point for `verify --dir <output-directory>`.
+fn run_verify(dir: &Path) -> Result<()> {
+    if !dir.is_dir() {
+        anyhow::bail!("Output directory does not exist: {}", dir.display());
+    }
+
+    let problems = verify_output_dir(dir)?;
+    for problem in &problems {
+        error!("{}", problem);
+    }
+    if !problems.is_empty() {
+        anyhow::bail!("{} problems found in {}", problems.len(), dir.display());
+    }
+
+    info!("All files in {} are valid", dir.display());
+    Ok(())
+}
+
+/// A tag found by one or more detectors in `consensus`.
+#[derive(Debug, Serialize, Deserialize)]
+struct ConsensusTag {
+    uid: String,
+    detectors: Vec<String>,
+    /// Number of detectors that found the tag.
+    #[serde(default)]
+    votes: usize,
+    /// Found by a single detector only.
+    #[serde(default)]
+    unconfirmed: bool,
+    /// Mean of the matched detections' centres.
+    centre: Corner,
+    /// Matched corners averaged with the decision margins as weights, in the
+    /// first detector's corner order.
+    #[serde(default)]
+    corners: Vec<Corner>,
+}
+
+/// Per-image entry of `consensus.json`.
+#[derive(Debug, Serialize, Deserialize)]
+struct ImageConsensus {
+    image: String,
+    agreed_by_all: Vec<ConsensusTag>,
+    agreed_by_some: Vec<ConsensusTag>,
+    unique: Vec<ConsensusTag>,
+}
+
+/// Cross-detector agreement written by the `consensus` subcommand.
+#[derive(Debug, Serialize, Deserialize)]
+struct Consensus {
+    detectors: Vec<String>,
+    tolerance_px: f32,
+    /// Quad overlap used for matching instead of `tolerance_px` (`--min-iou`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    min_iou: Option<f32>,
+    total_agreed_by_all: usize,
+    total_agreed_by_some: usize,
+    total_unique: usize,
+    images: Vec<ImageConsensus>,
+}
+
+/// Output files in a detector's directory that are not per-image results.
+const RUN_LEVEL_OUTPUTS: [&str; 9] = [
+    "manifest",
+    "summary",
+    "scoring",
+    "robustness",
+    "errors",
+    "consensus",
+    "results",
+    "detections",
+    "counts",
+];
+
+/// Loads every per-image result in `dir`, keyed by file stem so rotated
+/// copies of an image stay separate.
+fn load_detection_results(dir: &Path) -> Result<BTreeMap<String, Vec<Detection>>> {
+    let mut results = BTreeMap::new();
+    for entry in fs::read_dir(dir).context(format!("Failed to read {:?}", dir))? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        let Some(stem) = output_file_stem(name) else {
+            continue;
+        };
+        if RUN_LEVEL_OUTPUTS.contains(&stem) || stem.ends_with(".rejected") {
+            continue;
+        }
+
+        let result: DetectionResult = read_output(&path)
+            .context(format!("Failed to parse detection result {:?}", path))?;
+        results.insert(stem.to_string(), result.detections);
+    }
+    Ok(results)
+}
+
+/// Groups detections of the same tag from different detectors whose centres
+/// lie within `tolerance_px` of the first member, or with `min_iou` whose
+/// quads overlap it by at least that much. Each group holds at most one
+/// detection per detector, identified by its index in `per_detector`.
+fn cluster_detections<'a>(
+    per_detector: &[&'a [Detection]],
+    tolerance_px: f32,
+    min_iou: Option<f32>,
+) -> Vec<Vec<(usize, &'a Detection)>> {
+    let mut clusters: Vec<Vec<(usize, &Detection)>> = Vec::new();
+    for (detector, detections) in per_detector.iter().enumerate() {
+        for detection in detections.iter() {
+            let centre = detection.centre();
+            let matching = clusters.iter_mut().find(|cluster| {
+                let (_, first) = cluster[0];
+                let first_centre = first.centre();
+                let same_place = match min_iou {
+                    Some(min_iou) => quad_iou(&first.corners, &detection.corners) >= min_iou,
+                    None => (centre.x - first_centre.x).hypot(centre.y - first_centre.y) <= tolerance_px,
+                };
+                first.tag_family == detection.tag_family
+                    && first.tag_id == detection.tag_id
+                    && cluster.iter().all(|&(d, _)| d != detector)
+                    && same_place
+            });
+            match matching {
+                Some(cluster) => cluster.push((detector, detection)),
+                None => clusters.push(vec![(detector, detection)]),
+            }
+        }
+    }
+    clusters
+}
+
+/// Reorders `corners` to best line up with `reference`, since detectors may
+/// start from a different corner or wind the other way.
+fn align_corners(reference: &[Corner], corners: &[Corner]) -> Vec<Corner> {
+    let n = corners.len();
+    let mismatch = |candidate: &[Corner]| -> f32 {
+        reference
+            .iter()
+            .zip(candidate)
+            .map(|(a, b)| (a.x - b.x).powi(2) + (a.y - b.y).powi(2))
+            .sum()
+    };
+    let reversed: Vec<Corner> = corners.iter().rev().cloned().collect();
+    (0..n)
+        .flat_map(|shift| {
+            [corners, reversed.as_slice()]
+                .map(|order| (0..n).map(|i| order[(i + shift) % n].clone()).collect::<Vec<Corner>>())
+        })
+        .min_by(|a, b| mismatch(a).total_cmp(&mismatch(b)))
+        .unwrap_or_default()
+}
+
+/// Averages the corners of a cluster, weighting each detection by its decision
+/// margin so confident decodes dominate. Falls back to a plain mean when no
+/// detection has a positive margin.
+fn weighted_corners(cluster: &[(usize, &Detection)]) -> Vec<Corner> {
+    let (_, first) = cluster[0];
+    let total_margin: f32 = cluster.iter().map(|(_, d)| d.decision_margin.max(0.0)).sum();
+    let weight = |d: &Detection| {
+        if total_margin > 0.0 {
+            d.decision_margin.max(0.0) / total_margin
+        } else {
+            1.0 / cluster.len() as f32
+        }
+    };
+
+    let mut corners = vec![Corner { x: 0.0, y: 0.0 }; first.corners.len()];
+    for &(_, detection) in cluster {
+        let w = weight(detection);
+        for (sum, corner) in corners.iter_mut().zip(align_corners(&first.corners, &detection.corners)) {
+            sum.x += w * corner.x;
+            sum.y += w * corner.y;
+        }
+    }
+    corners
+}
+
+/// Entry point for `consensus --output <dir> [--tolerance <px> | --min-iou <f32>] <detector-output-dir>...`.
+fn run_consensus(args: ConsensusArgs) -> Result<()> {
+    let ConsensusArgs {
+        output,
+        tolerance: tolerance_px,
+        min_iou,
+        dirs,
+    } = args;
+    if min_iou.is_some_and(|iou| !(iou > 0.0 && iou <= 1.0)) {
+        anyhow::bail!("--min-iou must be in (0, 1]");
+    }
+
+    let detectors: Vec<String> = dirs
+        .iter()
+        .map(|dir| match dir.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => dir.display().to_string(),
+        })
+        .collect();
+    let results = dirs
+        .iter()
+        .map(|dir| load_detection_results(dir))
+        .collect::<Result<Vec<_>>>()?;
+    let images: BTreeSet<&str> = results.iter().flat_map(|r| r.keys().map(String::as_str)).collect();
+
+    let mut image_consensus = Vec::new();
+    for image in images {
+        let per_detector: Vec<&[Detection]> = results
+            .iter()
+            .map(|r| r.get(image).map_or(&[][..], |d| d.as_slice()))
+            .collect();
+
+        let mut entry = ImageConsensus {
+            image: image.to_string(),
+            agreed_by_all: Vec::new(),
+            agreed_by_some: Vec::new(),
+            unique: Vec::new(),
+        };
+        for cluster in cluster_detections(&per_detector, tolerance_px, min_iou) {
+            let n = cluster.len() as f32;
+            let (_, first) = cluster[0];
+            let tag = ConsensusTag {
+                uid: detection_uid(&first.tag_family, first.tag_id),
+                detectors: cluster.iter().map(|&(d, _)| detectors[d].clone()).collect(),
+                votes: cluster.len(),
+                unconfirmed: cluster.len() == 1,
+                centre: Corner {
+                    x: cluster.iter().map(|(_, d)| d.centre().x).sum::<f32>() / n,
+                    y: cluster.iter().map(|(_, d)| d.centre().y).sum::<f32>() / n,
+                },
+                corners: weighted_corners(&cluster),
+            };
+            match cluster.len() {
+                1 => entry.unique.push(tag),
+                votes if votes == detectors.len() => entry.agreed_by_all.push(tag),
+                _ => entry.agreed_by_some.push(tag),
+            }
+        }
+        image_consensus.push(entry);
+    }
+
+    let consensus = Consensus {
+        total_agreed_by_all: image_consensus.iter().map(|i| i.agreed_by_all.len()).sum(),
+        total_agreed_by_some: image_consensus.iter().map(|i| i.agreed_by_some.len()).sum(),
+        total_unique: image_consensus.iter().map(|i| i.unique.len()).sum(),
+        detectors,
+        tolerance_px,
+        min_iou,
+        images: image_consensus,
+    };
+    info!(
+        "{} tags agreed by all, {} by some, {} unique across {} images",
+        consensus.total_agreed_by_all,
+        consensus.total_agreed_by_some,
+        consensus.total_unique,
+        consensus.images.len()
+    );
+
+    fs::create_dir_all(&output).context("Failed to create output directory")?;
+    write_json(&output, "consensus", &consensus, false)?;
+    info!("Wrote consensus: {:?}", output.join("consensus.json"));
+    Ok(())
+}
+
+/// Picks the families with the most detections so far (`--auto-family`),
+/// keeping every family on a tie. Returns all families if nothing was detected.
+fn select_families(
+    families: &[(String, TagFamilyKind)],
+    family_detections: &HashMap<String, usize>,
+) -> Vec<(String, TagFamilyKind)> {
+    let count = |name: &String| family_detections.get(name).copied().unwrap_or(0);
+    let best = families.iter().map(|(name, _)| count(name)).max().unwrap_or(0);
+    if best == 0 {
+        warn!("No detections in the probe images, keeping all families");
+        return families.to_vec();
+    }
+
+    families
+        .iter()
+        .filter(|(name, _)| count(name) == best)
+        .cloned()
+        .collect()
+}
+
+/// One line of a `--tasks` file: the families to run for a single image.
+#[derive(Debug, Deserialize)]
+struct ImageTask {
+    image: String,
+    families: Vec<String>,
+}
+
+/// Loads a JSON-lines task file into a map from image filename to the families to run.
+fn load_tasks(
+    path: &Path,
+    families: &[(String, TagFamilyKind)],
+) -> Result<HashMap<String, Vec<(String, TagFamilyKind)>>> {
+    let contents = fs::read_to_string(path)
+        .context(format!("Failed to read task file {:?}", path))?;
+
+    let mut tasks = HashMap::new();
+    for (line_number, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let task: ImageTask = serde_json::from_str(line)
+            .context(format!("Invalid task on line {} of {:?}", line_number + 1, path))?;
+
+        let mut task_families = Vec::new();
+        for name in &task.families {
+            let family = families
+                .iter()
+                .find(|(family_name, _)| family_name == name)
+                .with_context(|| format!("Unknown family {} for image {} in task file", name, task.image))?;
+            task_families.push(family.clone());
+        }
+        tasks.insert(task.image, task_families);
+    }
+
+    Ok(tasks)
+}
+
+/// Exit status of a run that completed but found no tags in any image.
+const EXIT_NO_DETECTIONS: u8 = 2;
+/// Exit status of a run in which some images could not be processed.
+const EXIT_IMAGES_FAILED: u8 = 3;
+/// Exit status of a run whose input contained no images.
+const EXIT_NO_IMAGES: u8 = 4;
+
+/// Runs the detector and maps the outcome to an exit status: 0 for a run with
+/// detections, `EXIT_NO_DETECTIONS`, `EXIT_IMAGES_FAILED`, `EXIT_NO_IMAGES`,
+/// or 1 for any error that stops the run, such as invalid arguments or
+/// unwritable output.
+fn main() -> ExitCode {
+    match run() {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run() -> Result<ExitCode> {
+    let args: Vec<String> = std::env::args().collect();
+
+    // The usage line from before clap, kept for scripts that match on it;
+    // clap generates the option and subcommand help below it
+    let usage = format!("{} --input <input-directory> --output <output-directory>", args[0]);
+    // Usage errors exit with 1 rather than clap's 2, as scripts expect
+    let matches = Args::command()
+        .name(DETECTOR_NAME)
+        .version(env!("CARGO_PKG_VERSION"))
+        .override_usage(usage)
+        .try_get_matches_from(&args)
+        .unwrap_or_else(|e| {
+            if e.use_stderr() {
+                let _ = e.print();
+                std::process::exit(1);
+            }
+            e.exit()
+        });
+
+    let args = Args::from_arg_matches(&matches)?;
+    if let Some(command) = args.command {
+        init_logging(None, false, None)?;
+        return match command {
+            Command::Verify { dir } => run_verify(&dir),
+            Command::Consensus(consensus) => run_consensus(consensus),
+        }
+        .map(|()| ExitCode::SUCCESS);
+    }
+
+    let options = parse_args(args)?;
+    let progress = progress_bar(&options)?;
+    init_logging(options.log_level.as_deref(), options.quiet, progress.as_ref())?;
+    run_detection(options, progress)
+}
+
+/// Processes the run's input as configured by `options`, once logging is set up.
+fn run_detection(options: Options, progress: Option<ProgressBar>) -> Result<ExitCode> {
+    let run_start = Instant::now();
+    let input_dir = options.input.as_str();
+    let input_path = Path::new(input_dir);
+    let output_path = Path::new(&options.output);
+
+    // Collect all image paths first
+    let image_paths = if let Some(file_list) = &options.file_list {
+        let paths = read_file_list(file_list, input_path)?;
+        info!("Read {} image paths from {}", paths.len(), file_list);
+        paths
+    } else if is_glob_pattern(input_dir) {
+        if options.watch {
+            anyhow::bail!("--watch requires an input directory, not a glob pattern");
+        }
+        if options.recursive {
+            anyhow::bail!("--recursive requires an input directory, not a glob pattern");
+        }
+        let paths = collect_glob_paths(input_dir)?;
+        if paths.is_empty() {
+            anyhow::bail!("Input pattern matched no images: {}", input_dir);
+        }
+        paths
+    } else if input_path.is_file() {
+        if options.watch || options.recursive {
+            anyhow::bail!("--watch and --recursive require an input directory, not a single file");
+        }
+        if !is_supported_image(input_path) {
+            anyhow::bail!("Input file is not a supported image: {}", input_dir);
+        }
+        vec![input_path.to_path_buf()]
+    } else {
+        if !input_path.exists() {
+            anyhow::bail!("Input directory does not exist: {}", input_dir);
+        }
+        collect_directory_paths(input_path, options.recursive)?
+    };
+
+    if input_path.is_dir()
+        && input_path.canonicalize().ok().is_some_and(|input| output_path.canonicalize().ok() == Some(input))
+    {
+        warn!(
+            "--input and --output are the same directory {}; files written there could be picked up as inputs on a re-run",
+            input_dir
+        );
+    }
+
+    let (families, custom_min_hamming) = add_custom_families(get_supported_families(), &options)?;
+    let families = filter_families(families, &options.families)?;
+
+    // A dry run leaves the output directory untouched
+    if !options.dry_run {
+        create_output_dirs(output_path, &families, &options)?;
+    }
+
+    if image_paths.is_empty() && !options.watch {
+        if options.fail_on_empty {
+            anyhow::bail!("No images found in {}", input_dir);
+        }
+        warn!("No images found in {}", input_dir);
+        return Ok(ExitCode::from(EXIT_NO_IMAGES));
+    }
+
+    let tasks = match &options.tasks {
+        Some(tasks_path) => {
+            let tasks = load_tasks(Path::new(tasks_path), &families)?;
+            let image_names = image_paths
+                .iter()
+                .map(|p| image_name(p, &options))
+                .collect::<Result<HashSet<_>>>()?;
+            for image in tasks.keys() {
+                if !image_names.contains(image) {
+                    anyhow::bail!("Task file references an image not found in the input: {}", image);
+                }
+            }
+            tasks
+        }
+        None => HashMap::new(),
+    };
+
+    let pattern = options
+        .pattern
+        .as_deref()
+        .map(glob::Pattern::new)
+        .transpose()
+        .context("Invalid --pattern")?;
+    let image_paths = match &pattern {
+        Some(pattern) => {
+            let found = image_paths.len();
+            let matched: Vec<PathBuf> =
+                image_paths.into_iter().filter(|p| matches_pattern(p, Some(pattern))).collect();
+            info!(
+                "{} of {} images match --pattern {}, skipping {}",
+                matched.len(),
+                found,
+                pattern,
+                found - matched.len()
+            );
+            matched
+        }
+        None => image_paths,
+    };
+
+    // Sort before shuffling so the subset does not depend on directory order
+    let image_paths = match options.sample {
+        Some(n) => {
+            let mut paths = image_paths;
+            paths.sort();
+            paths.shuffle(&mut StdRng::seed_from_u64(options.seed));
+            paths.truncate(n);
+            info!("Sampled {} images with seed {}", paths.len(), options.seed);
+            paths
+        }
+        None => image_paths,
+    };
+    let image_paths = match options.limit {
+        Some(n) if image_paths.len() > n => {
+            info!("Limited to the first {} of {} images", n, image_paths.len());
+            let mut paths = image_paths;
+            paths.truncate(n);
+            paths
+        }
+        _ => image_paths,
+    };
+    check_output_collisions(&image_paths, &options)?;
+
+    if options.dry_run {
+        print_dry_run(&image_paths, &families, &tasks, &options, output_path)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    // Process each image and write output immediately
+    if let Some(progress) = &progress {
+        progress.set_length(image_paths.len() as u64);
+    }
+    let mut records = RunRecords {
+        progress: progress.clone(),
+        benchmark_csv: options
+            .benchmark_csv
+            .as_deref()
+            .map(|path| open_benchmark_csv(Path::new(path)))
+            .transpose()?,
+        ndjson: options
+            .ndjson
+            .as_deref()
+            .map(|path| fs::File::create(path).context(format!("Failed to create NDJSON output {:?}", path)))
+            .transpose()?,
+        ..RunRecords::default()
+    };
+    // Built on first use; images of one size then share decoders across the run
+    let mut decoders = DecoderCache::new();
+    let mut run_families = families.clone();
+    let mut auto_selected_families = None;
+
+    // Probe images run serially, since the remaining images depend on their results
+    let probe_count = options.auto_family.map_or(0, |k| k.min(image_paths.len()));
+    let (probe_paths, remaining_paths) = image_paths.split_at(probe_count);
+    for image_path in probe_paths {
+        handle_image(image_path, &run_families, &tasks, &options, output_path, &mut records, &mut decoders)?;
+    }
+    if probe_count > 0 {
+        run_families = select_families(&families, &records.family_detections);
+        let names: Vec<String> = run_families.iter().map(|(name, _)| name.clone()).collect();
+        info!("Auto-selected families: {}", names.join(", "));
+        auto_selected_families = Some(names);
+    }
+
+    if options.jobs > 1 {
+        handle_images_parallel(
+            remaining_paths,
+            &run_families,
+            &tasks,
+            &options,
+            output_path,
+            &mut records,
+            &mut decoders,
+        )?;
+    } else {
+        for image_path in remaining_paths {
+            handle_image(image_path, &run_families, &tasks, &options, output_path, &mut records, &mut decoders)?;
+        }
+    }
+    let mut processed_count = image_paths.len();
+    if let Some(progress) = &progress {
+        progress.finish_and_clear();
+    }
+
+    if options.watch {
+        processed_count += watch_input(
+            input_path,
+            image_paths.into_iter().collect(),
+            &run_families,
+            &tasks,
+            &options,
+            pattern.as_ref(),
+            output_path,
+            &mut records,
+            &mut decoders,
+        )?;
+    }
+
+    // Failed loads and timeouts alike, so the log adds up and matches the exit status
+    let attempted = processed_count - records.skipped_images;
+    info!(
+        "Processed {} images: {} succeeded, {} failed",
+        attempted,
+        attempted - records.failed_images,
+        records.failed_images
+    );
+    if options.skip_existing {
+        info!("Skipped {} images with existing output", records.skipped_images);
+    }
+    if options.unique_ids {
+        info!("Collapsed {} duplicate tag ids", records.duplicate_ids);
+    }
+
+    let summary = Summary::from_images(records.image_summaries, run_start.elapsed().as_secs_f64() * 1000.0);
+    print_summary(&summary);
+    if options.bench_only || options.summary {
+        write_json(output_path, "summary", &summary, false)?;
+        info!("Wrote summary: {:?}", output_path.join("summary.json"));
+    }
+
+    if options.ground_truth.is_some() {
+        let scoring = Scoring::from_images(records.image_scores, records.missing_ground_truth);
+        info!(
+            "Matched {} of {} expected detections ({} false positives)",
+            scoring.total_matched, scoring.total_ground_truth, scoring.total_false_positives
+        );
+        if !scoring.missing_ground_truth.is_empty() {
+            warn!("{} images had no ground truth and were not scored", scoring.missing_ground_truth.len());
+        }
+        write_json(output_path, "scoring", &scoring, false)?;
+        info!("Wrote scoring: {:?}", output_path.join("scoring.json"));
+    }
+
+    if options.combined {
+        info!("Writing {} combined results", records.combined_results.len());
+        write_json(output_path, "results", &records.combined_results, options.compress)?;
+    }
+
+    if options.format == OutputFormat::FlatJson {
+        info!("Writing {} detections", records.flat_detections.len());
+        write_json(output_path, "detections", &records.flat_detections, options.compress)?;
+    }
+
+    if options.counts_only {
+        // Stable sort keeps each image's families in run order, whatever order --jobs finished in
+        records.detection_counts.sort_by(|a, b| a.image.cmp(&b.image));
+        info!("Writing {} detection counts", records.detection_counts.len());
+        write_json(output_path, "counts", &records.detection_counts, options.compress)?;
+    }
+
+    if options.format == OutputFormat::Csv {
+        info!("Writing {} CSV rows", records.csv_rows.len());
+        write_detections_csv(output_path, &records.csv_rows, !options.rotations.is_empty())?;
+    }
+
+    if !options.jpeg_qualities.is_empty() {
+        let robustness = Robustness::from_images(&options.jpeg_qualities, records.image_robustness);
+        for quality in &robustness.qualities {
+            info!(
+                "JPEG quality {}: {} of {} baseline tags retained",
+                quality.quality, quality.total_retained, robustness.total_baseline
+            );
+        }
+        write_json(output_path, "robustness", &robustness, false)?;
+        info!("Wrote robustness: {:?}", output_path.join("robustness.json"));
+    }
+
+    if !records.image_errors.is_empty() {
+        error!("{} images had errors, see errors.json", records.image_errors.len());
+        let errors = ErrorLog {
+            errors: records.image_errors,
+        };
+        write_json(output_path, "errors", &errors, false)?;
+    }
+
+    // Write manifest
+    let manifest = Manifest {
+        schema_version: SCHEMA_VERSION,
+        detector: DETECTOR_NAME.to_string(),
+        supported_families: families.iter().map(|(name, _)| name.clone()).collect(),
+        family_directories: options
+            .split_by_family
+            .then(|| families.iter().map(|(name, _)| name.clone()).collect()),
+        peak_memory_mb: peak_memory_mb(),
+        compressed: options.compress,
+        format: (options.format != OutputFormat::Json).then(|| options.format.name().to_string()),
+        auto_selected_families,
+        partition_counts: options.partition_output.then_some(records.partition_counts),
+        combined: options.combined,
+        recursive: options.recursive,
+        decoder_config: families
+            .first()
+            .map(|(_, family_kind)| decode_config(family_kind, &options))
+            .transpose()?
+            .map(|config| DecoderConfig {
+                quad_decimate: config.downscale_factor,
+                decode_sharpening: config.decode_sharpening,
+                refine_edges: config.refine_edges_enabled,
+            }),
+        counts_only: options.counts_only,
+        gray_method: options.gray_method.name().to_string(),
+        families: families
+            .iter()
+            .map(|(name, kind)| family_info(name, kind, &custom_min_hamming, &options))
+            .collect::<Result<_>>()?,
+    };
+    if options.format == OutputFormat::Yaml {
+        write_yaml(output_path, "manifest", &manifest)?;
+        info!("Wrote manifest: {:?}", output_path.join("manifest.yaml"));
+    } else {
+        let manifest_path = output_path.join("manifest.json");
+        let manifest_json = serde_json::to_string_pretty(&manifest)?;
+        write_atomically(&manifest_path, |tmp_file| {
+            fs::write(tmp_file, manifest_json).context("Failed to write manifest")
+        })?;
+        info!("Wrote manifest: {:?}", manifest_path);
+    }
+
+    // Checked last so a failing run still leaves its outputs for inspection
+    if records.failed_images > 0 {
+        error!("{} images failed to process, see errors.json", records.failed_images);
+        return Ok(ExitCode::from(EXIT_IMAGES_FAILED));
+    }
+    if records.total_detections < options.min_total_detections {
+        anyhow::bail!(
+            "Expected at least {} detections in total, found {}",
+            options.min_total_detections,
+            records.total_detections
+        );
+    }
+    // A --skip-existing rerun that skipped every image has no detections of its own to report
+    if records.total_detections == 0 && attempted > 0 {
+        warn!("No tags were detected in any image");
+        return Ok(ExitCode::from(EXIT_NO_DETECTIONS));
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use kornia_detector_common::output::CANONICAL_TAG_CORNERS;
+
+    /// Tag images shared by the detector crates: tag36h11 id 0 in each input format.
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../fixtures");
+
+    /// Creates an empty directory for one test under the system temp directory.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("{}-{}-{}", DETECTOR_NAME, std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Copies the named fixtures into a fresh input directory.
+    fn fixture_input(name: &str, fixtures: &[&str]) -> PathBuf {
+        let dir = temp_dir(name);
+        for fixture in fixtures {
+            fs::copy(Path::new(FIXTURES).join(fixture), dir.join(fixture)).unwrap();
+        }
+        dir
+    }
+
+    fn options(args: &[&str]) -> Options {
+        let args = Args::try_parse_from(std::iter::once(DETECTOR_NAME).chain(args.iter().copied())).unwrap();
+        parse_args(args).unwrap()
+    }
+
+    fn run_on(input: &Path, output: &Path, extra_args: &[&str]) -> ExitCode {
+        let mut args = vec!["--input", input.to_str().unwrap(), "--output", output.to_str().unwrap()];
+        args.extend(["--families", "tag36h11"]);
+        args.extend(extra_args);
+        run_detection(options(&args), None).unwrap()
+    }
+
+    #[test]
+    fn png_and_jpeg_inputs_both_produce_json() {
+        let input = fixture_input("png-jpeg", &["tag36h11-gray8.png", "tag36h11-jpeg.jpg"]);
+        let output = temp_dir("png-jpeg-output");
+        assert_eq!(run_on(&input, &output, &[]), ExitCode::SUCCESS);
+
+        for stem in ["tag36h11-gray8", "tag36h11-jpeg"] {
+            let result: DetectionResult = read_json(&output.join(format!("{}.json", stem))).unwrap();
+            assert_eq!(result.error, None);
+            assert_eq!(result.detections.len(), 1, "{}", stem);
+            assert_eq!(result.detections[0].tag_id, 0);
+        }
+    }
+
+    #[test]
+    fn gray16_png_is_shifted_to_eight_bits_and_detected() {
+        let input = fixture_input("gray16", &["tag36h11-gray16.png"]);
+        let output = temp_dir("gray16-output");
+        assert_eq!(run_on(&input, &output, &[]), ExitCode::SUCCESS);
+
+        let result: DetectionResult = read_json(&output.join("tag36h11-gray16.json")).unwrap();
+        assert_eq!(result.error, None);
+        assert_eq!(result.bit_shift, Some(8));
+        assert_eq!(result.detections.len(), 1);
+        assert_eq!(result.detections[0].tag_id, 0);
+    }
+
+    #[test]
+    fn webp_input_is_detected() {
+        let input = fixture_input("webp", &["tag36h11-lossless.webp"]);
+        let output = temp_dir("webp-output");
+        assert_eq!(run_on(&input, &output, &[]), ExitCode::SUCCESS);
+
+        let result: DetectionResult = read_json(&output.join("tag36h11-lossless.json")).unwrap();
+        assert_eq!(result.error, None);
+        assert_eq!(result.detections.len(), 1);
+        assert_eq!(result.detections[0].tag_id, 0);
+    }
+
+    #[test]
+    fn rgb16_png_is_shifted_to_eight_bits_and_detected() {
+        let input = fixture_input("rgb16", &["tag36h11-rgb16.png"]);
+        let output = temp_dir("rgb16-output");
+        assert_eq!(run_on(&input, &output, &[]), ExitCode::SUCCESS);
+
+        let result: DetectionResult = read_json(&output.join("tag36h11-rgb16.json")).unwrap();
+        assert_eq!(result.error, None);
+        assert_eq!(result.bit_shift, Some(8));
+        assert_eq!(result.detections.len(), 1);
+        assert_eq!(result.detections[0].tag_id, 0);
+    }
+
+    #[test]
+    fn compressed_output_round_trips_through_read_json() {
+        let input = fixture_input("compress", &["tag36h11-gray8.png"]);
+        let output = temp_dir("compress-output");
+        assert_eq!(run_on(&input, &output, &["--compress"]), ExitCode::SUCCESS);
+
+        let path = output.join("tag36h11-gray8.json.gz");
+        assert!(!output.join("tag36h11-gray8.json").exists());
+        assert_eq!(fs::read(&path).unwrap()[..2], [0x1f, 0x8b]);
+
+        let result: DetectionResult = read_json(&path).unwrap();
+        assert_eq!(result.detections.len(), 1);
+        assert_eq!(result.detections[0].tag_id, 0);
+
+        write_json(&output, "copy", &result, true).unwrap();
+        let copy: DetectionResult = read_json(&output.join("copy.json.gz")).unwrap();
+        assert_eq!(serde_json::to_value(&copy).unwrap(), serde_json::to_value(&result).unwrap());
+    }
+
+    #[test]
+    fn jpeg_components_are_found_behind_large_app_segments() {
+        let jpeg = fs::read(Path::new(FIXTURES).join("tag36h11-jpeg.jpg")).unwrap();
+        // Three maximum-size APP15 segments push the frame header past 128 KiB
+        let mut padded = jpeg[..2].to_vec();
+        for _ in 0..3 {
+            padded.extend([0xFF, 0xEF, 0xFF, 0xFF]);
+            padded.extend(std::iter::repeat_n(0u8, 0xFFFF - 2));
+        }
+        padded.extend(&jpeg[2..]);
+
+        let dir = temp_dir("large-app");
+        let path = dir.join("padded.jpg");
+        fs::write(&path, padded).unwrap();
+        assert_eq!(read_jpeg_components(&path).unwrap(), 3);
+        assert_eq!(image_channels(&path).unwrap(), 3);
+    }
+
+    /// Input directory holding one plain white image without any tag.
+    fn blank_input(name: &str) -> PathBuf {
+        let dir = temp_dir(name);
+        image::GrayImage::from_pixel(64, 64, image::Luma([255])).save(dir.join("blank.png")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn run_without_detections_exits_with_no_detections_status() {
+        let input = blank_input("blank");
+        let output = temp_dir("blank-output");
+        assert_eq!(run_on(&input, &output, &[]), ExitCode::from(EXIT_NO_DETECTIONS));
+
+        // The minimum is checked before the no-detections status
+        let args = [
+            "--input",
+            input.to_str().unwrap(),
+            "--output",
+            output.to_str().unwrap(),
+            "--families",
+            "tag36h11",
+            "--expect-detections",
+        ];
+        assert!(run_detection(options(&args), None).is_err());
+    }
+
+    #[test]
+    fn skip_existing_rerun_of_every_image_succeeds() {
+        let input = blank_input("skip-existing");
+        let output = temp_dir("skip-existing-output");
+        assert_eq!(run_on(&input, &output, &[]), ExitCode::from(EXIT_NO_DETECTIONS));
+        assert_eq!(run_on(&input, &output, &["--skip-existing"]), ExitCode::SUCCESS);
+    }
+
+    #[test]
+    fn run_level_outputs_are_not_loaded_as_image_results() {
+        let input = fixture_input("run-level", &["tag36h11-gray8.png"]);
+        let output = temp_dir("run-level-output");
+        assert_eq!(run_on(&input, &output, &["--combined", "--summary"]), ExitCode::SUCCESS);
+        write_json(&output, "detections", &Vec::<FlatDetection>::new(), false).unwrap();
+        write_json(&output, "counts", &Vec::<DetectionCount>::new(), false).unwrap();
+
+        let results = load_detection_results(&output).unwrap();
+        assert_eq!(results.keys().collect::<Vec<_>>(), ["tag36h11-gray8"]);
+    }
+
+    #[test]
+    fn yaml_output_is_verified_and_loaded() {
+        let input = fixture_input("yaml", &["tag36h11-gray8.png"]);
+        let output = temp_dir("yaml-output");
+        assert_eq!(run_on(&input, &output, &["--format", "yaml"]), ExitCode::SUCCESS);
+        assert!(output.join("manifest.yaml").is_file());
+
+        assert_eq!(verify_output_dir(&output).unwrap(), Vec::<String>::new());
+        let results = load_detection_results(&output).unwrap();
+        assert_eq!(results.keys().collect::<Vec<_>>(), ["tag36h11-gray8"]);
+        assert_eq!(results["tag36h11-gray8"].len(), 1);
+    }
+
+    #[test]
+    fn summary_family_detections_add_up_after_scale_merging() {
+        let input = fixture_input("summary-merge", &["tag36h11-gray8.png"]);
+        let output = temp_dir("summary-merge-output");
+        assert_eq!(run_on(&input, &output, &["--summary", "--multi-scale", "1,0.9"]), ExitCode::SUCCESS);
+
+        let summary: Summary = read_json(&output.join("summary.json")).unwrap();
+        assert_eq!(summary.images[0].timings.family_timings.len(), 2);
+        assert_eq!(summary.total_detections, 1);
+        assert_eq!(summary.family_detections.values().sum::<usize>(), summary.total_detections);
+        assert_eq!(summary.images[0].family_detections["tag36h11"], 1);
+    }
+
+    #[test]
+    fn homography_follows_sorted_corners() {
+        let input = fixture_input("homography", &["tag36h11-gray8.png"]);
+        let output = temp_dir("homography-output");
+        let code = run_on(&input, &output, &["--emit-homography", "--sort-corners"]);
+        assert_eq!(code, ExitCode::SUCCESS);
+
+        let result: DetectionResult = read_json(&output.join("tag36h11-gray8.json")).unwrap();
+        let detection = &result.detections[0];
+        let h = detection.homography.unwrap();
+        for (&[u, v], corner) in CANONICAL_TAG_CORNERS.iter().zip(&detection.corners) {
+            let (u, v) = (u as f32, v as f32);
+            let w = h[2][0] * u + h[2][1] * v + h[2][2];
+            let x = (h[0][0] * u + h[0][1] * v + h[0][2]) / w;
+            let y = (h[1][0] * u + h[1][1] * v + h[1][2]) / w;
+            assert!((x - corner.x).abs() < 0.01 && (y - corner.y).abs() < 0.01, "{:?} vs ({}, {})", corner, x, y);
+        }
+    }
+
+    #[test]
+    fn jpeg_quality_sweep_degrades_the_detected_image() {
+        let input = fixture_input("quality-sweep", &["tag36h11-gray8.png"]);
+        let output = temp_dir("quality-sweep-output");
+        assert_eq!(run_on(&input, &output, &["--jpeg-quality-sweep", "90"]), ExitCode::SUCCESS);
+
+        let robustness: Robustness = read_json(&output.join("robustness.json")).unwrap();
+        let image = &robustness.images[0];
+        assert_eq!(image.baseline_count, 1);
+        assert_eq!(image.qualities[0].quality, 90);
+        assert_eq!(image.qualities[0].retained, 1);
+    }
+
+    #[test]
+    fn images_too_large_for_jpeg_are_rejected() {
+        let img = GrayImage::new(ImageSize { width: 70_000, height: 1 }, vec![0; 70_000], CpuAllocator).unwrap();
+        assert!(degrade_jpeg(&img, 90).is_err());
+    }
+
+    /// Writes `family` as a `--custom-family` definition file, with `min_hamming` if given.
+    fn write_custom_family(dir: &Path, family: &TagFamily, min_hamming: Option<u32>) -> PathBuf {
+        let mut definition = serde_json::json!({
+            "width_at_border": family.width_at_border,
+            "total_width": family.total_width,
+            "reversed_border": family.reversed_border,
+            "nbits": family.nbits,
+            "bit_x": family.bit_x,
+            "bit_y": family.bit_y,
+            "codes": family.code_data,
+        });
+        if let Some(min_hamming) = min_hamming {
+            definition["min_hamming"] = min_hamming.into();
+        }
+        let path = dir.join("family.json");
+        fs::write(&path, definition.to_string()).unwrap();
+        path
+    }
+
+    #[test]
+    fn custom_family_definition_needs_a_valid_min_hamming() {
+        let dir = temp_dir("custom-family-definition");
+        let family = TagFamily::tag36_h11();
+
+        let missing = write_custom_family(&dir, &family, None);
+        assert!(load_custom_family("mytags", &missing).is_err());
+        let zero = write_custom_family(&dir, &family, Some(0));
+        assert!(load_custom_family("mytags", &zero).is_err());
+        let valid = write_custom_family(&dir, &family, Some(11));
+        assert!(matches!(load_custom_family("mytags", &valid), Ok((TagFamilyKind::Custom(_), 11))));
+    }
+
+    #[test]
+    fn timed_out_image_is_recorded_as_failed() {
+        // Large enough that loading alone takes longer than the timeout
+        let input = temp_dir("timeout");
+        image::GrayImage::from_pixel(2048, 2048, image::Luma([255])).save(input.join("large.png")).unwrap();
+        let output = temp_dir("timeout-output");
+        assert_eq!(run_on(&input, &output, &["--per-image-timeout", "1"]), ExitCode::from(EXIT_IMAGES_FAILED));
+
+        let result: DetectionResult = read_json(&output.join("large.json")).unwrap();
+        assert_eq!(result.error.as_deref(), Some("Detection did not finish within 1 ms"));
+        assert!(result.detections.is_empty());
+        let errors: ErrorLog = read_json(&output.join("errors.json")).unwrap();
+        assert_eq!(errors.errors.len(), 1);
+        assert_eq!(errors.errors[0].reason, "timeout");
+    }
+
+    #[test]
+    fn benchmark_csv_quotes_fields() {
+        let input = temp_dir("benchmark-csv");
+        fs::copy(Path::new(FIXTURES).join("tag36h11-gray8.png"), input.join("tag, \"quoted\".png")).unwrap();
+        let output = temp_dir("benchmark-csv-output");
+        let csv_path = output.join("benchmark.csv");
+        let args = ["--bench-only", "--benchmark-csv", csv_path.to_str().unwrap()];
+        assert_eq!(run_on(&input, &output, &args), ExitCode::SUCCESS);
+
+        let mut reader = csv::Reader::from_path(&csv_path).unwrap();
+        assert_eq!(reader.headers().unwrap(), BENCHMARK_CSV_HEADER.as_slice());
+        let rows: Vec<csv::StringRecord> = reader.records().map(Result::unwrap).collect();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(&rows[0][0], "tag, \"quoted\".png");
+        assert_eq!(&rows[0][1], DETECTOR_NAME);
+        assert_eq!(&rows[0][2], "tag36h11");
+        assert_eq!(&rows[0][3], "1");
+    }
+
+    #[test]
+    fn families_after_a_per_family_timeout_are_not_started() {
+        // Large enough that decoding takes longer than the timeout
+        let input = temp_dir("family-timeout");
+        image::GrayImage::from_pixel(2048, 2048, image::Luma([255])).save(input.join("large.png")).unwrap();
+        let output = temp_dir("family-timeout-output");
+        let args = ["--families", "tag16h5", "--per-family-timeout", "1"];
+        assert_eq!(run_on(&input, &output, &args), ExitCode::from(EXIT_NO_DETECTIONS));
+
+        let result: DetectionResult = read_json(&output.join("large.json")).unwrap();
+        let timings = &result.timings.family_timings;
+        assert_eq!(timings.len(), 2);
+        assert!(timings.iter().all(|t| t.timed_out));
+        assert_eq!(timings[1].detection_ms, 0.0);
+    }
+
+    #[test]
+    fn manifest_min_hamming_comes_from_the_table_and_the_definition_file() {
+        let input = fixture_input("manifest-min-hamming", &["tag36h11-gray8.png"]);
+        let output = temp_dir("manifest-min-hamming-output");
+        // Named without an h<n> suffix, and with a distance that differs from tag36h11's
+        let definition = write_custom_family(&temp_dir("manifest-min-hamming-family"), &TagFamily::tag36_h11(), Some(9));
+        let custom_family = format!("mytags={}", definition.to_str().unwrap());
+        let args = ["--custom-family", custom_family.as_str(), "--families", "mytags"];
+        assert_eq!(run_on(&input, &output, &args), ExitCode::SUCCESS);
+
+        let manifest: Manifest = read_json(&output.join("manifest.json")).unwrap();
+        let min_hamming = |name: &str| manifest.families.iter().find(|family| family.name == name).unwrap().min_hamming;
+        assert_eq!(min_hamming("tag36h11"), 11);
+        assert_eq!(min_hamming("mytags"), 9);
+    }
+}
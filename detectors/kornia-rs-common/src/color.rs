@@ -0,0 +1,76 @@
+//! RGB-to-gray conversions selectable with `--gray-method`, and the
+//! `--bit-shift` reduction of 16-bit samples to 8 bits.
+
+use anyhow::Result;
+
+/// Formula turning a colour pixel into the gray value the decoder sees.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GrayMethod {
+    /// ITU-R BT.601 luma: 0.299 R + 0.587 G + 0.114 B.
+    Bt601,
+    /// ITU-R BT.709 luma: 0.2126 R + 0.7152 G + 0.0722 B.
+    Bt709,
+    /// Unweighted mean of the three channels.
+    Average,
+    /// The green channel alone.
+    Green,
+}
+
+impl GrayMethod {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "bt601" => Ok(GrayMethod::Bt601),
+            "bt709" => Ok(GrayMethod::Bt709),
+            "average" => Ok(GrayMethod::Average),
+            "green" => Ok(GrayMethod::Green),
+            _ => anyhow::bail!("Unknown --gray-method: {} (expected bt601, bt709, average or green)", value),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            GrayMethod::Bt601 => "bt601",
+            GrayMethod::Bt709 => "bt709",
+            GrayMethod::Average => "average",
+            GrayMethod::Green => "green",
+        }
+    }
+
+    /// Converts interleaved 8-bit RGB pixels to one gray byte each, rounding
+    /// to the nearest value.
+    pub fn to_gray(&self, rgb: &[u8]) -> Vec<u8> {
+        let [wr, wg, wb] = match self {
+            GrayMethod::Bt601 => [0.299, 0.587, 0.114],
+            GrayMethod::Bt709 => [0.2126, 0.7152, 0.0722],
+            GrayMethod::Average => [1.0 / 3.0; 3],
+            GrayMethod::Green => [0.0, 1.0, 0.0],
+        };
+        rgb.chunks_exact(3)
+            .map(|px| {
+                let gray = wr * f32::from(px[0]) + wg * f32::from(px[1]) + wb * f32::from(px[2]);
+                gray.round().clamp(0.0, 255.0) as u8
+            })
+            .collect()
+    }
+}
+
+/// Reduces 16-bit samples to 8 bits by right-shifting by `bit_shift`, saturating at 255.
+pub fn shift_to_8bit(pixels: &[u16], bit_shift: u32) -> Vec<u8> {
+    pixels
+        .iter()
+        .map(|&v| (v >> bit_shift).min(u8::MAX as u16) as u8)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sixteen_bit_samples_are_shifted_and_saturated() {
+        let pixels = [0, 255, 256, 0x8000, u16::MAX];
+        assert_eq!(shift_to_8bit(&pixels, 8), vec![0, 0, 1, 128, 255]);
+        assert_eq!(shift_to_8bit(&pixels, 4), vec![0, 15, 16, 255, 255]);
+        assert_eq!(shift_to_8bit(&pixels, 0), vec![0, 255, 255, 255, 255]);
+    }
+}
@@ -0,0 +1,136 @@
+//! Rendering of detections onto a copy of the input image (`--draw`).
+
+use crate::output::{quad_center, Corner, Detection};
+
+/// Outline colours of the known families, in `--families` order.
+const FAMILY_COLORS: [(&str, [u8; 3]); 9] = [
+    ("tag36h11", [230, 25, 75]),
+    ("tag36h10", [60, 180, 75]),
+    ("tag25h9", [0, 130, 200]),
+    ("tag16h5", [245, 130, 48]),
+    ("tagCircle21h7", [145, 30, 180]),
+    ("tagCircle49h12", [70, 240, 240]),
+    ("tagCustom48h12", [240, 50, 230]),
+    ("tagStandard41h12", [210, 245, 60]),
+    ("tagStandard52h13", [255, 225, 25]),
+];
+
+/// 3x5 bitmaps of the digits 0-9, one row per byte with the leftmost pixel in bit 2.
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b001, 0b001, 0b001],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+
+/// Pixel size of one font dot.
+const FONT_SCALE: i64 = 3;
+
+/// Outline colour for a family, white for families without an assigned colour.
+pub fn family_color(family: &str) -> [u8; 3] {
+    FAMILY_COLORS
+        .iter()
+        .find(|(name, _)| *name == family)
+        .map_or([255, 255, 255], |(_, color)| *color)
+}
+
+/// An interleaved 8-bit RGB image to draw on.
+pub struct RgbCanvas {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl RgbCanvas {
+    /// Copies a row-major 8-bit grayscale image into every channel.
+    pub fn from_gray(width: usize, height: usize, gray: &[u8]) -> Self {
+        RgbCanvas {
+            width,
+            height,
+            pixels: gray.iter().flat_map(|&v| [v, v, v]).collect(),
+        }
+    }
+
+    fn put(&mut self, x: i64, y: i64, color: [u8; 3]) {
+        if x < 0 || y < 0 || x >= self.width as i64 || y >= self.height as i64 {
+            return;
+        }
+        let i = (y as usize * self.width + x as usize) * 3;
+        self.pixels[i..i + 3].copy_from_slice(&color);
+    }
+
+    fn fill_rect(&mut self, x: i64, y: i64, w: i64, h: i64, color: [u8; 3]) {
+        for py in y..y + h {
+            for px in x..x + w {
+                self.put(px, py, color);
+            }
+        }
+    }
+
+    /// Bresenham line two pixels wide, clipped to the canvas.
+    fn line(&mut self, from: &Corner, to: &Corner, color: [u8; 3]) {
+        let (mut x, mut y) = (from.x.round() as i64, from.y.round() as i64);
+        let (x1, y1) = (to.x.round() as i64, to.y.round() as i64);
+        let (dx, dy) = ((x1 - x).abs(), -(y1 - y).abs());
+        let (sx, sy) = (if x < x1 { 1 } else { -1 }, if y < y1 { 1 } else { -1 });
+        let mut err = dx + dy;
+        loop {
+            self.fill_rect(x, y, 2, 2, color);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y += sy;
+            }
+        }
+    }
+
+    /// Writes `number` centred on `(cx, cy)` over a black backing box.
+    fn number(&mut self, cx: f32, cy: f32, number: u16, color: [u8; 3]) {
+        let digits: Vec<usize> = number.to_string().bytes().map(|b| usize::from(b - b'0')).collect();
+        let advance = 4 * FONT_SCALE;
+        let (w, h) = (digits.len() as i64 * advance - FONT_SCALE, 5 * FONT_SCALE);
+        let (x0, y0) = (cx.round() as i64 - w / 2, cy.round() as i64 - h / 2);
+
+        let pad = FONT_SCALE;
+        self.fill_rect(x0 - pad, y0 - pad, w + 2 * pad, h + 2 * pad, [0, 0, 0]);
+        for (i, &digit) in digits.iter().enumerate() {
+            for (row, bits) in DIGITS[digit].iter().enumerate() {
+                for col in 0..3 {
+                    if bits & (0b100 >> col) != 0 {
+                        let x = x0 + i as i64 * advance + col * FONT_SCALE;
+                        let y = y0 + row as i64 * FONT_SCALE;
+                        self.fill_rect(x, y, FONT_SCALE, FONT_SCALE, color);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Outlines the quad in its family's colour, marks the first corner with a
+    /// square so the orientation is visible, and labels the centre with the tag id.
+    pub fn draw_detection(&mut self, detection: &Detection) {
+        let color = family_color(&detection.tag_family);
+        let corners = &detection.corners;
+        for (a, b) in corners.iter().zip(corners.iter().cycle().skip(1)) {
+            self.line(a, b, color);
+        }
+        if let Some(first) = corners.first() {
+            self.fill_rect(first.x.round() as i64 - 3, first.y.round() as i64 - 3, 7, 7, color);
+        }
+        let centre = quad_center(corners);
+        self.number(centre.x, centre.y, detection.tag_id, color);
+    }
+}
@@ -0,0 +1,10 @@
+//! Output types, command line handling and pixel helpers shared by the
+//! kornia-rs detector binaries.
+//!
+//! Each binary pins its own kornia-rs revision, so nothing here depends on
+//! kornia: the tag family mapping and the detection itself stay in the binaries.
+
+pub mod color;
+pub mod draw;
+pub mod options;
+pub mod output;
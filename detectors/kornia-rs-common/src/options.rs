@@ -0,0 +1,793 @@
+//! Command line flags of the detectors and the checks that turn them into the
+//! run's [`Options`].
+
+use anyhow::{Context, Result};
+use clap::builder::RangedU64ValueParser;
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::color::GrayMethod;
+use crate::output::{CoordinateOrigin, Corner, OutputFormat};
+
+/// Rejection reason for tags below `--min-decision-margin`.
+pub const LOW_MARGIN: &str = "low_decision_margin";
+
+/// Thresholds applied to decoded tags on top of the decoder's own checks.
+#[derive(Debug, Clone, Default)]
+pub struct DetectionFilter {
+    pub min_decision_margin: f32,
+    pub max_hamming: Option<u8>,
+}
+
+impl DetectionFilter {
+    /// Returns the reason code for dropping a detection, or `None` to keep it.
+    pub fn rejection_reason(&self, decision_margin: f32, hamming: u8) -> Option<&'static str> {
+        if self.max_hamming.is_some_and(|max| hamming > max) {
+            return Some("hamming_too_high");
+        }
+        if decision_margin < self.min_decision_margin {
+            return Some(LOW_MARGIN);
+        }
+        None
+    }
+}
+
+/// Physical parameters for the pinhole distance estimate (`--tag-size`, `--focal`).
+#[derive(Debug, Clone, Copy)]
+pub struct DistanceModel {
+    pub tag_size_m: f64,
+    pub focal_px: f64,
+}
+
+impl DistanceModel {
+    /// Estimates the camera-to-tag distance from the quad's mean edge length,
+    /// assuming the tag faces the camera.
+    pub fn distance_m(&self, corners: &[Corner]) -> f64 {
+        let perimeter_px: f64 = corners
+            .iter()
+            .zip(corners.iter().cycle().skip(1))
+            .map(|(a, b)| f64::from(b.x - a.x).hypot(f64::from(b.y - a.y)))
+            .sum();
+        let mean_edge_px = perimeter_px / corners.len() as f64;
+        self.focal_px * self.tag_size_m / mean_edge_px
+    }
+}
+
+/// Camera intrinsics and tag size for pose estimation (`--fx`, `--fy`, `--cx`,
+/// `--cy`, `--tag-size`).
+///
+/// The camera frame has x right, y down and z forward. The tag frame is centred
+/// on the tag with x right and y down across its face and z pointing into it, so
+/// a tag squarely facing the camera has the identity rotation.
+#[derive(Debug, Clone, Copy)]
+pub struct PoseModel {
+    pub fx: f64,
+    pub fy: f64,
+    pub cx: f64,
+    pub cy: f64,
+    pub tag_size_m: f64,
+}
+
+pub type Vec3 = [f64; 3];
+
+fn norm3(v: Vec3) -> f64 {
+    (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt()
+}
+
+fn scale3(v: Vec3, k: f64) -> Vec3 {
+    [v[0] * k, v[1] * k, v[2] * k]
+}
+
+impl PoseModel {
+    /// Decomposes the tag-to-image homography into a rotation and translation.
+    /// Lens distortion is ignored. Returns `None` for degenerate homographies.
+    pub fn estimate(&self, homography: &[[f32; 3]; 3]) -> Option<([[f64; 3]; 3], Vec3)> {
+        // K^-1 H, with the canonical (-1, -1)..(1, 1) square rescaled to metres
+        // and its y axis flipped to point down the tag
+        let half = self.tag_size_m / 2.0;
+        let column = |c: usize, scale: f64| {
+            let [h0, h1, h2] = [0, 1, 2].map(|r| f64::from(homography[r][c]) * scale);
+            [(h0 - self.cx * h2) / self.fx, (h1 - self.cy * h2) / self.fy, h2]
+        };
+        let (r1, r2, t) = (column(0, 1.0 / half), column(1, -1.0 / half), column(2, 1.0));
+
+        // The homography is only known up to scale; pick the one that gives unit
+        // rotation columns and puts the tag in front of the camera
+        let lambda = 1.0 / (norm3(r1) * norm3(r2)).sqrt();
+        if !lambda.is_finite() {
+            return None;
+        }
+        let lambda = if t[2] < 0.0 { -lambda } else { lambda };
+        let (r1, r2, t) = (scale3(r1, lambda), scale3(r2, lambda), scale3(t, lambda));
+
+        // Corner noise leaves r1 and r2 slightly skewed, so orthonormalise them
+        let x = scale3(r1, 1.0 / norm3(r1));
+        let along_x = x[0] * r2[0] + x[1] * r2[1] + x[2] * r2[2];
+        let y = [r2[0] - along_x * x[0], r2[1] - along_x * x[1], r2[2] - along_x * x[2]];
+        let y = scale3(y, 1.0 / norm3(y));
+        let z = [x[1] * y[2] - x[2] * y[1], x[2] * y[0] - x[0] * y[2], x[0] * y[1] - x[1] * y[0]];
+
+        Some(([[x[0], y[0], z[0]], [x[1], y[1], z[1]], [x[2], y[2], z[2]]], t))
+    }
+}
+
+#[derive(Clone)]
+pub struct Options {
+    pub input: String,
+    pub file_list: Option<String>,
+    pub output: String,
+    pub include_rejected: bool,
+    pub split_by_family: bool,
+    pub partition_output: bool,
+    pub combined: bool,
+    pub recursive: bool,
+    pub flatten_names: bool,
+    pub sort_corners: bool,
+    pub normalize_winding: bool,
+    pub emit_bbox: bool,
+    pub emit_homography: bool,
+    pub hash_inputs: bool,
+    pub skip_existing: bool,
+    pub compress: bool,
+    pub bench_only: bool,
+    pub watch: bool,
+    pub bit_shift: u32,
+    pub grayscale_input: bool,
+    pub gray_method: GrayMethod,
+    pub ignore_exif: bool,
+    pub read_retries: u32,
+    pub repeat: usize,
+    pub jobs: usize,
+    pub family_parallel: bool,
+    pub per_image_timeout: Option<Duration>,
+    pub per_family_timeout: Option<Duration>,
+    pub auto_family: Option<usize>,
+    pub min_total_detections: usize,
+    pub fail_on_empty: bool,
+    pub families: Vec<String>,
+    pub custom_families: Vec<(String, String)>,
+    pub rotations: Vec<f32>,
+    pub scales: Vec<f32>,
+    pub max_image_dimension: Option<usize>,
+    pub dedupe_iou: Option<f32>,
+    pub unique_ids: bool,
+    pub refine_edges: Option<bool>,
+    pub quad_decimate: Option<usize>,
+    pub decode_sharpening: Option<f32>,
+    pub coord_precision: Option<u32>,
+    pub format: OutputFormat,
+    pub csv_empty_rows: bool,
+    pub coordinate_origin: CoordinateOrigin,
+    pub jpeg_qualities: Vec<u8>,
+    pub sample: Option<usize>,
+    pub seed: u64,
+    pub limit: Option<usize>,
+    pub pattern: Option<String>,
+    pub distance: Option<DistanceModel>,
+    pub pose: Option<PoseModel>,
+    pub log_level: Option<String>,
+    pub quiet: bool,
+    pub dry_run: bool,
+    pub summary: bool,
+    pub tasks: Option<String>,
+    pub ground_truth: Option<String>,
+    pub benchmark_csv: Option<String>,
+    pub ndjson: Option<String>,
+    pub counts_only: bool,
+    pub draw: Option<String>,
+    pub filter: DetectionFilter,
+}
+
+/// Detect AprilTags in a directory of images with kornia-rs.
+#[derive(Parser)]
+#[command(version, subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Input directory, a single image file, or a glob pattern matching image files
+    #[arg(long = "input", required_unless_present = "file_list")]
+    pub input_dir: Option<String>,
+    /// Process the newline-separated image paths in this file (- for stdin) instead of scanning --input
+    #[arg(long)]
+    pub file_list: Option<String>,
+    /// Directory to write the results to
+    #[arg(long = "output", required = true)]
+    pub output_dir: Option<String>,
+    /// Drop detections below this decision margin
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub min_decision_margin: f32,
+    /// Drop detections that needed more than this many corrected bits
+    #[arg(long)]
+    pub max_hamming: Option<u8>,
+    /// Also write the dropped detections to <image>.rejected.json
+    #[arg(long)]
+    pub include_rejected: bool,
+    /// Write one subdirectory of results per family
+    #[arg(long)]
+    pub split_by_family: bool,
+    /// Write results into detected/ or empty/
+    #[arg(long)]
+    pub partition_output: bool,
+    /// Also write every result of the run to results.json
+    #[arg(long)]
+    pub combined: bool,
+    /// Include images in subdirectories of the input directory
+    #[arg(long)]
+    pub recursive: bool,
+    /// Prefix output names with the image's parent directory name
+    #[arg(long)]
+    pub flatten_names: bool,
+    /// JSONL file listing the families to run for specific images
+    #[arg(long)]
+    pub tasks: Option<String>,
+    /// Run only these tag families
+    #[arg(long, value_name = "NAME,...", value_delimiter = ',')]
+    pub families: Vec<String>,
+    /// Load a custom tag family definition from a JSON file under this name (repeatable)
+    #[arg(long = "custom-family", value_name = "NAME=PATH")]
+    pub custom_families: Vec<String>,
+    /// Reorder corners by image geometry
+    #[arg(long)]
+    pub sort_corners: bool,
+    /// Reverse clockwise quads so corners are always counter-clockwise
+    #[arg(long)]
+    pub normalize_winding: bool,
+    /// Add an axis-aligned bounding box to each detection
+    #[arg(long)]
+    pub emit_bbox: bool,
+    /// Add a tag-to-image homography to each detection
+    #[arg(long)]
+    pub emit_homography: bool,
+    /// Output layout: json, flat-json, csv or yaml
+    #[arg(long, default_value = "json", value_parser = OutputFormat::parse)]
+    pub format: OutputFormat,
+    /// With --format csv, also write a row with empty detection columns for images without detections
+    #[arg(long)]
+    pub csv_empty_rows: bool,
+    /// Corner coordinate origin: topleft (pixels, y down) or center (y up)
+    #[arg(long, default_value = "topleft", value_parser = CoordinateOrigin::parse)]
+    pub coordinate_origin: CoordinateOrigin,
+    /// Round written pixel coordinates to this many decimal places
+    #[arg(long, value_parser = clap::value_parser!(u32).range(0..=6))]
+    pub coord_precision: Option<u32>,
+    /// Write per-image results gzip-compressed
+    #[arg(long)]
+    pub compress: bool,
+    /// Record the SHA-256 of each input file
+    #[arg(long)]
+    pub hash_inputs: bool,
+    /// Skip images whose output already exists
+    #[arg(long)]
+    pub skip_existing: bool,
+    /// Reprocess every image and replace existing output (the default)
+    #[arg(long)]
+    pub overwrite: bool,
+    /// Only time detection and write summary.json
+    #[arg(long)]
+    pub bench_only: bool,
+    /// Right shift reducing 16-bit PNGs and TIFFs to 8 bits
+    #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(u32).range(0..=15))]
+    pub bit_shift: u32,
+    /// Require single-channel inputs and skip the RGB-to-gray conversion
+    #[arg(long)]
+    pub grayscale_input: bool,
+    /// RGB-to-gray formula: bt601, bt709, average or green
+    #[arg(long, default_value = "bt601", value_parser = GrayMethod::parse)]
+    pub gray_method: GrayMethod,
+    /// Detect in JPEGs as stored, without applying their EXIF orientation
+    #[arg(long)]
+    pub ignore_exif: bool,
+    /// Decode each image this many times per family
+    #[arg(
+        long,
+        visible_alias = "runs",
+        default_value_t = 1,
+        value_parser = RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    pub repeat: usize,
+    /// Worker threads detecting images in parallel [default: logical CPUs]
+    #[arg(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub jobs: Option<usize>,
+    /// Detect the families of each image in parallel
+    #[arg(long)]
+    pub family_parallel: bool,
+    /// Retry a failed image read this many times, waiting longer after each attempt
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub read_retries: u32,
+    /// Skip images that take longer than this many milliseconds
+    #[arg(long, value_name = "MS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub per_image_timeout: Option<u64>,
+    /// Give up on a family's decode after this many milliseconds
+    #[arg(
+        long,
+        visible_alias = "per-family-timeout-ms",
+        value_name = "MS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub per_family_timeout: Option<u64>,
+    /// Also detect in copies rotated by these angles in degrees
+    #[arg(long, value_name = "DEG,...", value_delimiter = ',', allow_hyphen_values = true)]
+    pub rotations: Vec<f32>,
+    /// Detect at each of these scale factors in (0, 1] and merge the results
+    #[arg(long = "multi-scale", value_name = "SCALE,...", value_delimiter = ',')]
+    pub scales: Vec<f32>,
+    /// Downscale images whose longer side exceeds this many pixels before detection
+    #[arg(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub max_image_dimension: Option<usize>,
+    /// Keep only the highest-margin detection of quads overlapping across families
+    #[arg(long)]
+    pub dedupe: bool,
+    /// Overlap above which --dedupe treats quads as the same tag [default: 0.7]
+    #[arg(long, requires = "dedupe")]
+    pub dedupe_iou: Option<f32>,
+    /// Keep only the highest-margin detection of each tag id per family
+    #[arg(long)]
+    pub unique_ids: bool,
+    /// Measure detections retained after re-encoding at these JPEG qualities
+    #[arg(
+        long = "jpeg-quality-sweep",
+        value_name = "Q,...",
+        value_delimiter = ',',
+        value_parser = clap::value_parser!(u8).range(1..=100)
+    )]
+    pub jpeg_qualities: Vec<u8>,
+    /// Override the decoder's edge refinement: on or off
+    #[arg(long, value_parser = parse_on_off)]
+    pub refine_edges: Option<bool>,
+    /// Override the decoder's quad detection downscale factor
+    #[arg(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub quad_decimate: Option<usize>,
+    /// Override the decoder's sharpening of the sampled tag bits
+    #[arg(long)]
+    pub decode_sharpening: Option<f32>,
+    /// Process a random subset of this many images
+    #[arg(long)]
+    pub sample: Option<usize>,
+    /// Seed for --sample
+    #[arg(long, default_value_t = 0)]
+    pub seed: u64,
+    /// Process only the first this many images, in file name order
+    #[arg(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub limit: Option<usize>,
+    /// Only process images whose file name matches this glob
+    #[arg(long)]
+    pub pattern: Option<String>,
+    /// Probe all families on this many images, then keep the best
+    #[arg(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub auto_family: Option<usize>,
+    /// Physical tag size in metres, for distance_m and the pose
+    #[arg(long = "tag-size")]
+    pub tag_size_m: Option<f64>,
+    /// Focal length in pixels, for distance_m
+    #[arg(long = "focal", requires = "tag_size_m")]
+    pub focal_px: Option<f64>,
+    /// Horizontal focal length in pixels, for the pose
+    #[arg(long, requires = "fy")]
+    pub fx: Option<f64>,
+    /// Vertical focal length in pixels, for the pose
+    #[arg(long, requires = "cx")]
+    pub fy: Option<f64>,
+    /// Principal point x in pixels, for the pose
+    #[arg(long, requires = "cy")]
+    pub cx: Option<f64>,
+    /// Principal point y in pixels, for the pose
+    #[arg(long, requires = "fx")]
+    pub cy: Option<f64>,
+    /// Directory of expected detections to score against
+    #[arg(long)]
+    pub ground_truth: Option<String>,
+    /// Append per-family detection timings to this CSV file
+    #[arg(long)]
+    pub benchmark_csv: Option<String>,
+    /// Write results as one JSON line each to this file instead of per-image files
+    #[arg(long)]
+    pub ndjson: Option<String>,
+    /// Write only the number of detections per image and family to counts.json
+    #[arg(long)]
+    pub counts_only: bool,
+    /// Save a PNG of each image with its detections outlined into this directory
+    #[arg(long, value_name = "DIR")]
+    pub draw: Option<String>,
+    /// Fail unless the run produced at least one detection
+    #[arg(long)]
+    pub expect_detections: bool,
+    /// Fail unless the run produced at least this many detections
+    #[arg(long)]
+    pub min_total_detections: Option<usize>,
+    /// Fail if the input contains no images
+    #[arg(long)]
+    pub fail_on_empty: bool,
+    /// Log filter, e.g. debug or info
+    #[arg(long)]
+    pub log_level: Option<String>,
+    /// Hide the progress bar and per-image log lines
+    #[arg(long)]
+    pub quiet: bool,
+    /// List the images and families that would be processed, then exit
+    #[arg(long)]
+    pub dry_run: bool,
+    /// Also write summary.json outside --bench-only
+    #[arg(long)]
+    pub summary: bool,
+    /// Keep processing new images as they appear until Ctrl-C
+    #[arg(long)]
+    pub watch: bool,
+}
+
+/// Tools that work on existing output directories instead of detecting tags.
+#[derive(Subcommand)]
+pub enum Command {
+    /// Check that every output file in a directory parses as its result type
+    Verify {
+        /// Output directory of a previous run
+        #[arg(long)]
+        dir: PathBuf,
+    },
+    /// Cross-validate the output directories of several detectors run on the same images
+    Consensus(ConsensusArgs),
+}
+
+/// Flags of the `consensus` subcommand.
+#[derive(clap::Args)]
+pub struct ConsensusArgs {
+    /// Directory to write consensus.json to
+    #[arg(long)]
+    pub output: PathBuf,
+    /// Maximum distance between the centres of matching detections, in pixels
+    #[arg(long, default_value_t = 5.0, conflicts_with = "min_iou")]
+    pub tolerance: f32,
+    /// Match detections by quad overlap of at least this intersection over union instead
+    #[arg(long)]
+    pub min_iou: Option<f32>,
+    /// Output directories of the detectors to compare, at least two
+    #[arg(required = true, num_args = 2.., value_name = "DETECTOR_OUTPUT_DIR")]
+    pub dirs: Vec<PathBuf>,
+}
+
+fn parse_on_off(value: &str) -> Result<bool, String> {
+    match value {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err("expected on or off".to_string()),
+    }
+}
+
+/// Checks the flag combinations clap cannot express and builds the run's [`Options`].
+pub fn parse_args(args: Args) -> Result<Options> {
+    if args.scales.iter().any(|&scale| scale.is_nan() || scale <= 0.0 || scale > 1.0) {
+        anyhow::bail!("--multi-scale factors must be in (0, 1]");
+    }
+
+    if args.max_image_dimension.is_some() && !args.scales.is_empty() {
+        anyhow::bail!("--max-image-dimension cannot be combined with --multi-scale");
+    }
+
+    if args.dedupe_iou.is_some_and(|iou| !(iou > 0.0 && iou <= 1.0)) {
+        anyhow::bail!("--dedupe-iou must be in (0, 1]");
+    }
+    let dedupe_iou = args.dedupe.then(|| args.dedupe_iou.unwrap_or(0.7));
+    if dedupe_iou.is_some() && args.bench_only {
+        anyhow::bail!("--dedupe needs the detections, so it cannot be combined with --bench-only");
+    }
+
+    if args.unique_ids && args.bench_only {
+        anyhow::bail!("--unique-ids needs the detections, so it cannot be combined with --bench-only");
+    }
+
+    if args.tag_size_m.is_some() && args.focal_px.is_none() && args.fx.is_none() {
+        anyhow::bail!("--tag-size requires --focal or --fx/--fy/--cx/--cy");
+    }
+    let distance = match (args.tag_size_m, args.focal_px) {
+        (Some(tag_size_m), Some(focal_px)) => {
+            if tag_size_m <= 0.0 || focal_px <= 0.0 {
+                anyhow::bail!("--tag-size and --focal must be positive");
+            }
+            Some(DistanceModel { tag_size_m, focal_px })
+        }
+        _ => None,
+    };
+    if args.fx.is_some() && args.tag_size_m.is_none() {
+        anyhow::bail!("--fx/--fy/--cx/--cy require --tag-size");
+    }
+    let pose = match (args.tag_size_m, args.fx, args.fy, args.cx, args.cy) {
+        (Some(tag_size_m), Some(fx), Some(fy), Some(cx), Some(cy)) => {
+            if tag_size_m <= 0.0 || fx <= 0.0 || fy <= 0.0 {
+                anyhow::bail!("--tag-size, --fx and --fy must be positive");
+            }
+            Some(PoseModel { fx, fy, cx, cy, tag_size_m })
+        }
+        _ => None,
+    };
+
+    if args.decode_sharpening.is_some_and(|s| s.is_nan() || s < 0.0) {
+        anyhow::bail!("--decode-sharpening must not be negative");
+    }
+
+    if args.dry_run && args.watch {
+        anyhow::bail!("--dry-run cannot be combined with --watch");
+    }
+
+    if args.quiet && args.log_level.is_some() {
+        anyhow::bail!("--quiet cannot be combined with --log-level");
+    }
+
+    if args.flatten_names && args.recursive {
+        anyhow::bail!("--flatten-names cannot be combined with --recursive, whose outputs already mirror the input directories");
+    }
+
+    if args.file_list.is_some() && (args.watch || args.recursive) {
+        anyhow::bail!("--file-list cannot be combined with --watch or --recursive");
+    }
+
+    if args.recursive && args.watch {
+        anyhow::bail!("--recursive cannot be combined with --watch");
+    }
+
+    if args.sample.is_some() && args.watch {
+        anyhow::bail!("--sample cannot be combined with --watch");
+    }
+
+    if args.limit.is_some() && args.watch {
+        anyhow::bail!("--limit cannot be combined with --watch");
+    }
+
+    if !args.jpeg_qualities.is_empty() && (args.bench_only || !args.rotations.is_empty()) {
+        anyhow::bail!("--jpeg-quality-sweep cannot be combined with --bench-only or --rotations");
+    }
+
+    let format = args.format;
+    if format != OutputFormat::Json && (args.split_by_family || args.include_rejected || args.bench_only) {
+        anyhow::bail!(
+            "--format {} cannot be combined with --split-by-family, --include-rejected or --bench-only",
+            format.name()
+        );
+    }
+
+    if matches!(format, OutputFormat::Csv | OutputFormat::Yaml) && args.compress {
+        anyhow::bail!("--format {} cannot be combined with --compress", format.name());
+    }
+
+    if args.csv_empty_rows && format != OutputFormat::Csv {
+        anyhow::bail!("--csv-empty-rows requires --format csv");
+    }
+
+    if args.overwrite && args.skip_existing {
+        anyhow::bail!("--overwrite cannot be combined with --skip-existing");
+    }
+
+    if args.skip_existing && (args.bench_only || format != OutputFormat::Json) {
+        anyhow::bail!("--skip-existing needs per-image output, so it cannot be combined with --bench-only or --format");
+    }
+
+    if args.auto_family.is_some() && args.bench_only {
+        anyhow::bail!("--auto-family cannot be combined with --bench-only");
+    }
+
+    if args.combined && (args.skip_existing || args.bench_only || format != OutputFormat::Json) {
+        anyhow::bail!("--combined cannot be combined with --skip-existing, --bench-only or --format");
+    }
+
+    if args.partition_output && (args.split_by_family || args.bench_only || format != OutputFormat::Json) {
+        anyhow::bail!(
+            "--partition-output cannot be combined with --split-by-family, --bench-only or --format"
+        );
+    }
+
+    if args.ndjson.is_some()
+        && (args.split_by_family
+            || args.partition_output
+            || args.include_rejected
+            || args.skip_existing
+            || args.bench_only
+            || args.compress
+            || format != OutputFormat::Json)
+    {
+        anyhow::bail!(
+            "--ndjson replaces the per-image files, so it cannot be combined with --split-by-family, \
+             --partition-output, --include-rejected, --skip-existing, --bench-only, --compress or --format"
+        );
+    }
+
+    if args.counts_only
+        && (args.split_by_family
+            || args.partition_output
+            || args.combined
+            || args.include_rejected
+            || args.skip_existing
+            || args.bench_only
+            || args.ndjson.is_some()
+            || format != OutputFormat::Json)
+    {
+        anyhow::bail!(
+            "--counts-only replaces the per-image files, so it cannot be combined with --split-by-family, \
+             --partition-output, --combined, --include-rejected, --skip-existing, --bench-only, --ndjson or --format"
+        );
+    }
+
+    if args.draw.is_some() && args.bench_only {
+        anyhow::bail!("--draw needs the detections, so it cannot be combined with --bench-only");
+    }
+
+    if args.ground_truth.is_some() && args.bench_only {
+        anyhow::bail!("--ground-truth cannot be combined with --bench-only");
+    }
+
+    let families: Vec<String> = args
+        .families
+        .iter()
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect();
+    if families.is_empty() && !args.families.is_empty() {
+        anyhow::bail!("--families must list at least one tag family");
+    }
+
+    let mut custom_families = Vec::new();
+    for value in &args.custom_families {
+        match value.split_once('=') {
+            Some((name, path)) if !name.trim().is_empty() && !path.is_empty() => {
+                custom_families.push((name.trim().to_string(), path.to_string()));
+            }
+            _ => anyhow::bail!("--custom-family expects NAME=PATH, got {}", value),
+        }
+    }
+
+    Ok(Options {
+        input: args.input_dir.unwrap_or_default(),
+        file_list: args.file_list,
+        output: args.output_dir.context("--output is required")?,
+        include_rejected: args.include_rejected,
+        split_by_family: args.split_by_family,
+        partition_output: args.partition_output,
+        combined: args.combined,
+        recursive: args.recursive,
+        flatten_names: args.flatten_names,
+        sort_corners: args.sort_corners,
+        normalize_winding: args.normalize_winding,
+        emit_bbox: args.emit_bbox,
+        emit_homography: args.emit_homography,
+        hash_inputs: args.hash_inputs,
+        skip_existing: args.skip_existing,
+        compress: args.compress,
+        bench_only: args.bench_only,
+        watch: args.watch,
+        bit_shift: args.bit_shift,
+        grayscale_input: args.grayscale_input,
+        gray_method: args.gray_method,
+        ignore_exif: args.ignore_exif,
+        read_retries: args.read_retries,
+        repeat: args.repeat,
+        jobs: args
+            .jobs
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get())),
+        family_parallel: args.family_parallel,
+        per_image_timeout: args.per_image_timeout.map(Duration::from_millis),
+        per_family_timeout: args.per_family_timeout.map(Duration::from_millis),
+        auto_family: args.auto_family,
+        min_total_detections: args
+            .min_total_detections
+            .unwrap_or(0)
+            .max(usize::from(args.expect_detections)),
+        fail_on_empty: args.fail_on_empty,
+        families,
+        custom_families,
+        rotations: args.rotations,
+        scales: args.scales,
+        max_image_dimension: args.max_image_dimension,
+        dedupe_iou,
+        unique_ids: args.unique_ids,
+        refine_edges: args.refine_edges,
+        quad_decimate: args.quad_decimate,
+        decode_sharpening: args.decode_sharpening,
+        coord_precision: args.coord_precision,
+        format,
+        csv_empty_rows: args.csv_empty_rows,
+        coordinate_origin: args.coordinate_origin,
+        jpeg_qualities: args.jpeg_qualities,
+        sample: args.sample,
+        seed: args.seed,
+        limit: args.limit,
+        pattern: args.pattern,
+        distance,
+        pose,
+        log_level: args.log_level,
+        quiet: args.quiet,
+        dry_run: args.dry_run,
+        summary: args.summary,
+        tasks: args.tasks,
+        ground_truth: args.ground_truth,
+        benchmark_csv: args.benchmark_csv,
+        ndjson: args.ndjson,
+        counts_only: args.counts_only,
+        draw: args.draw,
+        filter: DetectionFilter {
+            min_decision_margin: args.min_decision_margin,
+            max_hamming: args.max_hamming,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::homography_from_corners;
+    use std::path::Path;
+
+    #[test]
+    fn fronto_parallel_tag_has_identity_rotation_and_expected_depth() {
+        let model = PoseModel {
+            fx: 800.0,
+            fy: 800.0,
+            cx: 320.0,
+            cy: 240.0,
+            tag_size_m: 0.2,
+        };
+        // A 0.2 m tag 2 m straight ahead spans 80 px; corners from the bottom-left, counter-clockwise
+        let corners = [(280.0, 280.0), (360.0, 280.0), (360.0, 200.0), (280.0, 200.0)].map(|(x, y)| Corner { x, y });
+        let homography = homography_from_corners(&corners).unwrap();
+
+        let (rotation, translation) = model.estimate(&homography).unwrap();
+        for (r, row) in rotation.iter().enumerate() {
+            for (c, &value) in row.iter().enumerate() {
+                let expected = if r == c { 1.0 } else { 0.0 };
+                assert!((value - expected).abs() < 1e-6, "rotation {:?}", rotation);
+            }
+        }
+        assert!(translation[0].abs() < 1e-6 && translation[1].abs() < 1e-6, "translation {:?}", translation);
+        assert!((translation[2] - 2.0).abs() < 1e-6, "translation {:?}", translation);
+    }
+
+    fn parse(extra: &[&str]) -> Result<Options> {
+        let base = ["detector", "--input", "images", "--output", "results"];
+        let args = Args::try_parse_from(base.iter().chain(extra))?;
+        parse_args(args)
+    }
+
+    #[test]
+    fn bit_shift_is_limited_to_sixteen_bit_range() {
+        assert_eq!(parse(&[]).unwrap().bit_shift, 8);
+        assert_eq!(parse(&["--bit-shift", "0"]).unwrap().bit_shift, 0);
+        assert_eq!(parse(&["--bit-shift", "15"]).unwrap().bit_shift, 15);
+        assert!(parse(&["--bit-shift", "16"]).is_err());
+    }
+
+    #[test]
+    fn conflicting_flags_are_rejected() {
+        for extra in [
+            &["--overwrite", "--skip-existing"][..],
+            &["--dedupe", "--dedupe-iou", "0"],
+            &["--dedupe", "--dedupe-iou", "1.5"],
+            &["--decode-sharpening=-0.5"],
+            &["--tag-size", "0.1"],
+            &["--dry-run", "--watch"],
+            &["--ndjson", "results.ndjson", "--compress"],
+        ] {
+            assert!(parse(extra).is_err(), "{:?} was accepted", extra);
+        }
+    }
+
+    #[test]
+    fn verify_subcommand_needs_no_detection_flags() {
+        let args = Args::try_parse_from(["detector", "verify", "--dir", "results"]).unwrap();
+        assert!(matches!(args.command, Some(Command::Verify { dir }) if dir == Path::new("results")));
+
+        assert!(Args::try_parse_from(["detector", "verify"]).is_err());
+        assert!(Args::try_parse_from(["detector", "--output", "results", "verify", "--dir", "results"]).is_err());
+    }
+
+    #[test]
+    fn consensus_subcommand_takes_at_least_two_directories() {
+        let args = Args::try_parse_from(["detector", "consensus", "--output", "out", "--min-iou", "0.5", "a", "b"]).unwrap();
+        let Some(Command::Consensus(consensus)) = args.command else {
+            panic!("consensus was not parsed as a subcommand");
+        };
+        assert_eq!(consensus.output, Path::new("out"));
+        assert_eq!(consensus.tolerance, 5.0);
+        assert_eq!(consensus.min_iou, Some(0.5));
+        assert_eq!(consensus.dirs, [Path::new("a"), Path::new("b")]);
+
+        assert!(Args::try_parse_from(["detector", "consensus", "--output", "out", "a"]).is_err());
+        assert!(Args::try_parse_from(["detector", "consensus", "--tolerance", "3", "--min-iou", "0.5", "--output", "out", "a", "b"]).is_err());
+    }
+}
@@ -0,0 +1,1077 @@
+//! The JSON output layout shared by the kornia-rs detectors, described in
+//! `schema/detection-format.json` and `schema/README.md`.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Corner {
+    pub x: f32,
+    pub y: f32,
+}
+
+/// Axis-aligned bounding box of a detection's corners, in pixels.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BoundingBox {
+    pub x: f32,
+    pub y: f32,
+    pub w: f32,
+    pub h: f32,
+}
+
+impl BoundingBox {
+    pub fn from_corners(corners: &[Corner]) -> Self {
+        let x_min = corners.iter().map(|c| c.x).fold(f32::INFINITY, f32::min);
+        let x_max = corners.iter().map(|c| c.x).fold(f32::NEG_INFINITY, f32::max);
+        let y_min = corners.iter().map(|c| c.y).fold(f32::INFINITY, f32::min);
+        let y_max = corners.iter().map(|c| c.y).fold(f32::NEG_INFINITY, f32::max);
+
+        BoundingBox {
+            x: x_min,
+            y: y_min,
+            w: x_max - x_min,
+            h: y_max - y_min,
+        }
+    }
+
+    /// Shrinks the box to lie within a `width` x `height` image.
+    pub fn clamp_to(&mut self, width: f32, height: f32) {
+        let (x_max, y_max) = ((self.x + self.w).clamp(0.0, width), (self.y + self.h).clamp(0.0, height));
+        self.x = self.x.clamp(0.0, width);
+        self.y = self.y.clamp(0.0, height);
+        self.w = x_max - self.x;
+        self.h = y_max - self.y;
+    }
+}
+
+/// Tag-space corners the homography maps onto the detected corners, in the
+/// same order, following the reference library's `(-1, -1)`..`(1, 1)` square.
+pub const CANONICAL_TAG_CORNERS: [[f64; 2]; 4] = [[-1.0, -1.0], [1.0, -1.0], [1.0, 1.0], [-1.0, 1.0]];
+
+/// Row-major 3x3 homography taking tag coordinates to image coordinates, fitted
+/// exactly to the four corner correspondences with `h[2][2] = 1`. Returns `None`
+/// for degenerate quads.
+pub fn homography_from_corners(corners: &[Corner]) -> Option<[[f32; 3]; 3]> {
+    if corners.len() != 4 {
+        return None;
+    }
+
+    // Two equations per correspondence in the eight unknowns h00..h21
+    let mut system = [[0.0f64; 9]; 8];
+    for (i, ([u, v], corner)) in CANONICAL_TAG_CORNERS.iter().zip(corners).enumerate() {
+        let (x, y) = (f64::from(corner.x), f64::from(corner.y));
+        system[2 * i] = [*u, *v, 1.0, 0.0, 0.0, 0.0, -u * x, -v * x, x];
+        system[2 * i + 1] = [0.0, 0.0, 0.0, *u, *v, 1.0, -u * y, -v * y, y];
+    }
+
+    // Gaussian elimination with partial pivoting
+    for col in 0..8 {
+        let pivot = (col..8).max_by(|&a, &b| system[a][col].abs().total_cmp(&system[b][col].abs()))?;
+        if system[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        system.swap(col, pivot);
+        let pivot_row = system[col];
+        for (row, equation) in system.iter_mut().enumerate() {
+            if row != col {
+                let factor = equation[col] / pivot_row[col];
+                for (k, value) in equation.iter_mut().enumerate().skip(col) {
+                    *value -= factor * pivot_row[k];
+                }
+            }
+        }
+    }
+
+    let h = |i: usize| (system[i][8] / system[i][i]) as f32;
+    Some([[h(0), h(1), h(2)], [h(3), h(4), h(5)], [h(6), h(7), 1.0]])
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Detection {
+    /// Stable join key, formatted as `"{tag_family}:{tag_id}"`.
+    #[serde(default)]
+    pub uid: String,
+    pub tag_id: u16,
+    pub tag_family: String,
+    pub corners: Vec<Corner>,
+    /// Intersection of the quad diagonals, see [`quad_center`].
+    #[serde(default)]
+    pub center: Corner,
+    /// Also the weight for `scene_centroid`.
+    #[serde(default)]
+    pub decision_margin: f32,
+    /// Bits the decoder corrected to match the tag code.
+    #[serde(default)]
+    pub hamming: u8,
+    /// Area enclosed by the corners in square pixels.
+    #[serde(default)]
+    pub area: f32,
+    /// Mean length of the longer pair of opposite edges over the shorter pair.
+    #[serde(default)]
+    pub aspect_ratio: f32,
+    /// Winding of `corners` as seen on screen, "ccw" or "cw".
+    #[serde(default)]
+    pub winding: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bbox: Option<BoundingBox>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub distance_m: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scale: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refine_delta: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub homography: Option<[[f32; 3]; 3]>,
+    /// Tag origin in the camera frame, in metres, see [`PoseModel`](crate::options::PoseModel).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub translation: Option<[f64; 3]>,
+    /// Row-major rotation taking tag-frame axes to the camera frame.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rotation: Option<[[f64; 3]; 3]>,
+}
+
+impl Detection {
+    /// Computed from the corners rather than read from `center`, which older
+    /// outputs lack.
+    pub fn centre(&self) -> Corner {
+        quad_center(&self.corners)
+    }
+
+    /// Moves every pixel-space field of the detection to centre-origin
+    /// coordinates, see [`centre_origin`]. Pose and distance are unaffected.
+    pub fn to_centre_origin(&mut self, width: f32, height: f32) {
+        for corner in &mut self.corners {
+            *corner = centre_origin(corner, width, height);
+        }
+        self.center = centre_origin(&self.center, width, height);
+        if let Some(bbox) = &mut self.bbox {
+            // The flip turns the top edge of the box into its bottom edge
+            bbox.x -= width / 2.0;
+            bbox.y = height / 2.0 - bbox.y - bbox.h;
+        }
+        if let Some(h) = &mut self.homography {
+            // Left-multiply by the same translate-and-flip; the last row is unchanged
+            let [row0, row1, row2] = h;
+            for ((h0, h1), h2) in row0.iter_mut().zip(row1.iter_mut()).zip(row2.iter()) {
+                *h0 -= width / 2.0 * h2;
+                *h1 = height / 2.0 * h2 - *h1;
+            }
+        }
+    }
+}
+
+/// Sorts an image's detections by family and id for stable output regardless of
+/// decoder and family iteration order, with the centre breaking ties between
+/// sightings of the same tag.
+pub fn sort_detections(detections: &mut [Detection]) {
+    detections.sort_by(|a, b| {
+        (&a.tag_family, a.tag_id)
+            .cmp(&(&b.tag_family, b.tag_id))
+            .then(a.center.y.total_cmp(&b.center.y))
+            .then(a.center.x.total_cmp(&b.center.x))
+    });
+}
+
+/// Area of a simple polygon from the shoelace formula.
+pub fn polygon_area(points: &[Corner]) -> f32 {
+    shoelace_sum(points).abs() / 2.0
+}
+
+/// Twice the signed area of a polygon. With y pointing down, on-screen
+/// counter-clockwise winding gives a negative sum.
+fn shoelace_sum(points: &[Corner]) -> f32 {
+    points
+        .iter()
+        .zip(points.iter().cycle().skip(1))
+        .map(|(a, b)| a.x * b.y - b.x * a.y)
+        .sum()
+}
+
+/// Winding of a quad as seen on screen, `"ccw"` or `"cw"`.
+pub fn quad_winding(corners: &[Corner]) -> &'static str {
+    if shoelace_sum(corners) > 0.0 {
+        "cw"
+    } else {
+        "ccw"
+    }
+}
+
+/// Makes a clockwise quad counter-clockwise by reversing the corners after the
+/// first, so the tag's own starting corner is kept.
+pub fn normalize_winding(corners: &mut [Corner]) {
+    if shoelace_sum(corners) > 0.0 {
+        corners[1..].reverse();
+    }
+}
+
+/// Reorders four corners counter-clockwise (as seen on screen) starting from
+/// the bottom-left, based purely on their image positions.
+pub fn sort_corners_ccw(corners: &mut [Corner]) {
+    let n = corners.len() as f32;
+    let cx = corners.iter().map(|c| c.x).sum::<f32>() / n;
+    let cy = corners.iter().map(|c| c.y).sum::<f32>() / n;
+
+    // Image y points down, so flip it to get screen-space angles
+    let angle = |c: &Corner| (cy - c.y).atan2(c.x - cx);
+    corners.sort_by(|a, b| angle(a).total_cmp(&angle(b)));
+
+    // Start from the corner closest to the bottom-left direction (-135 degrees)
+    let bottom_left = -3.0 * std::f32::consts::FRAC_PI_4;
+    let angular_distance = |c: &Corner| {
+        let d = (angle(c) - bottom_left).abs();
+        d.min(2.0 * std::f32::consts::PI - d)
+    };
+    if let Some(start) = (0..corners.len())
+        .min_by(|&a, &b| angular_distance(&corners[a]).total_cmp(&angular_distance(&corners[b])))
+    {
+        corners.rotate_left(start);
+    }
+}
+
+/// Intersection of the quad's diagonals, which unlike the corner mean stays on
+/// the tag centre under perspective. Falls back to the corner mean when the
+/// diagonals are parallel or the quad does not have four corners.
+pub fn quad_center(corners: &[Corner]) -> Corner {
+    let n = corners.len() as f32;
+    let mean = Corner {
+        x: corners.iter().map(|c| c.x).sum::<f32>() / n,
+        y: corners.iter().map(|c| c.y).sum::<f32>() / n,
+    };
+    let [p0, p1, p2, p3] = corners else {
+        return mean;
+    };
+
+    // Solve p0 + t * (p2 - p0) = p1 + u * (p3 - p1) for t
+    let (d1x, d1y) = (p2.x - p0.x, p2.y - p0.y);
+    let (d2x, d2y) = (p3.x - p1.x, p3.y - p1.y);
+    let denom = d1x * d2y - d1y * d2x;
+    if denom.abs() < 1e-6 {
+        return mean;
+    }
+    let t = ((p1.x - p0.x) * d2y - (p1.y - p0.y) * d2x) / denom;
+
+    Corner {
+        x: p0.x + t * d1x,
+        y: p0.y + t * d1y,
+    }
+}
+
+/// Decision-margin-weighted mean of the detection centres, or `None` without
+/// detections. Falls back to equal weights if every margin is zero.
+pub fn scene_centroid(detections: &[Detection]) -> Option<Corner> {
+    if detections.is_empty() {
+        return None;
+    }
+
+    let total_margin: f32 = detections.iter().map(|d| d.decision_margin.max(0.0)).sum();
+    let weight = |d: &Detection| {
+        if total_margin > 0.0 {
+            d.decision_margin.max(0.0) / total_margin
+        } else {
+            1.0 / detections.len() as f32
+        }
+    };
+
+    Some(detections.iter().fold(Corner { x: 0.0, y: 0.0 }, |acc, d| {
+        let centre = d.centre();
+        Corner {
+            x: acc.x + weight(d) * centre.x,
+            y: acc.y + weight(d) * centre.y,
+        }
+    }))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FamilyTiming {
+    pub family: String,
+    pub initialization_ms: f64,
+    pub detection_ms: f64,
+    pub detection_cpu_ms: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeat_stats: Option<RepeatStats>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scale: Option<f32>,
+    /// Whether decoding was abandoned after `--per-family-timeout`, leaving no detections.
+    #[serde(default)]
+    pub timed_out: bool,
+    /// Kept detections for this family, used by `--benchmark-csv`; not part of the output.
+    #[serde(skip)]
+    pub detection_count: usize,
+}
+
+/// Spread of `detection_ms` over repeated decodes of the same image.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RepeatStats {
+    pub runs: usize,
+    pub min_ms: f64,
+    /// Missing from files written before it was added.
+    #[serde(default)]
+    pub median_ms: f64,
+    pub mean_ms: f64,
+    pub stddev_ms: f64,
+}
+
+impl RepeatStats {
+    pub fn from_samples(samples_ms: &[f64]) -> Self {
+        let runs = samples_ms.len();
+        let mean_ms = samples_ms.iter().sum::<f64>() / runs as f64;
+        let variance = samples_ms.iter().map(|t| (t - mean_ms).powi(2)).sum::<f64>() / runs as f64;
+
+        let mut sorted = samples_ms.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        let median_ms = if runs.is_multiple_of(2) {
+            (sorted[runs / 2 - 1] + sorted[runs / 2]) / 2.0
+        } else {
+            sorted[runs / 2]
+        };
+
+        RepeatStats {
+            runs,
+            min_ms: sorted[0],
+            median_ms,
+            mean_ms,
+            stddev_ms: variance.sqrt(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Timings {
+    pub image_load_ms: f64,
+    /// Decoder construction, kept out of `total_detection_ms`.
+    #[serde(default)]
+    pub total_initialization_ms: f64,
+    pub total_detection_ms: f64,
+    pub family_timings: Vec<FamilyTiming>,
+}
+
+/// Version of the output file layout, bumped whenever its shape changes.
+/// Files written before versioning deserialize as `0`.
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DetectionResult {
+    #[serde(default)]
+    pub schema_version: u32,
+    #[serde(default)]
+    pub detector: String,
+    pub image: String,
+    pub detections: Vec<Detection>,
+    pub timings: Timings,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bit_shift: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rotation_deg: Option<f32>,
+    /// Size of the image the corners refer to, after any EXIF orientation and
+    /// rotation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_width: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_height: Option<usize>,
+    /// Channels stored in the file; detection always runs on one gray channel.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_channels: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scene_centroid: Option<Corner>,
+    /// Tags the decoder returned before harness-side filtering (`--include-rejected` only).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decoded_tags: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_sha256: Option<String>,
+    /// Detections dropped by `--min-decision-margin` (only when it is above zero).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filtered_count: Option<usize>,
+    /// Lower-margin repeats of a family's tag id dropped by `--unique-ids`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duplicate_ids: Option<usize>,
+    /// Why the image could not be processed; such results have no detections.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// [`CoordinateOrigin`] name of the corner coordinates.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coordinate_system: Option<String>,
+    /// Wall-clock time for the whole image: hashing, loading, every family and
+    /// all post-processing. Each rotation counts the shared loading once.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub processing_ms: Option<f64>,
+}
+
+impl DetectionResult {
+    /// Returns a copy of this result restricted to a single tag family.
+    pub fn for_family(&self, family: &str) -> DetectionResult {
+        let family_timings: Vec<FamilyTiming> = self
+            .timings
+            .family_timings
+            .iter()
+            .filter(|t| t.family == family)
+            .cloned()
+            .collect();
+        let total_initialization_ms = family_timings.iter().map(|t| t.initialization_ms).sum();
+        let total_detection_ms = family_timings.iter().map(|t| t.detection_ms).sum();
+
+        let detections: Vec<Detection> = self
+            .detections
+            .iter()
+            .filter(|d| d.tag_family == family)
+            .cloned()
+            .collect();
+
+        DetectionResult {
+            schema_version: self.schema_version,
+            detector: self.detector.clone(),
+            image: self.image.clone(),
+            scene_centroid: scene_centroid(&detections),
+            detections,
+            timings: Timings {
+                image_load_ms: self.timings.image_load_ms,
+                total_initialization_ms,
+                total_detection_ms,
+                family_timings,
+            },
+            bit_shift: self.bit_shift,
+            rotation_deg: self.rotation_deg,
+            image_width: self.image_width,
+            image_height: self.image_height,
+            image_channels: self.image_channels,
+            decoded_tags: None,
+            image_sha256: self.image_sha256.clone(),
+            filtered_count: None,
+            duplicate_ids: None,
+            error: self.error.clone(),
+            coordinate_system: self.coordinate_system.clone(),
+            processing_ms: self.processing_ms,
+        }
+    }
+}
+
+/// Per-image entry of `summary.json`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImageSummary {
+    pub image: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rotation_deg: Option<f32>,
+    pub detection_count: usize,
+    /// `detection_count` broken down by family.
+    #[serde(default)]
+    pub family_detections: BTreeMap<String, usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filtered_count: Option<usize>,
+    pub timings: Timings,
+}
+
+/// Run-level summary written to `summary.json`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Summary {
+    pub total_images: usize,
+    pub total_detections: usize,
+    pub total_image_load_ms: f64,
+    #[serde(default)]
+    pub total_initialization_ms: f64,
+    pub total_detection_ms: f64,
+    /// Kept detections per family, counted like `total_detections`.
+    #[serde(default)]
+    pub family_detections: BTreeMap<String, usize>,
+    #[serde(default)]
+    pub mean_detections_per_image: f64,
+    /// Elapsed time of the whole run, including loading and writing outputs.
+    #[serde(default)]
+    pub wall_clock_ms: f64,
+    pub images: Vec<ImageSummary>,
+}
+
+impl Summary {
+    pub fn from_images(images: Vec<ImageSummary>, wall_clock_ms: f64) -> Self {
+        let total_detections: usize = images.iter().map(|s| s.detection_count).sum();
+        let mut family_detections = BTreeMap::new();
+        for (family, count) in images.iter().flat_map(|s| &s.family_detections) {
+            *family_detections.entry(family.clone()).or_default() += count;
+        }
+
+        Summary {
+            total_images: images.len(),
+            total_detections,
+            total_image_load_ms: images.iter().map(|s| s.timings.image_load_ms).sum(),
+            total_initialization_ms: images.iter().map(|s| s.timings.total_initialization_ms).sum(),
+            total_detection_ms: images.iter().map(|s| s.timings.total_detection_ms).sum(),
+            family_detections,
+            mean_detections_per_image: if images.is_empty() {
+                0.0
+            } else {
+                total_detections as f64 / images.len() as f64
+            },
+            wall_clock_ms,
+            images,
+        }
+    }
+}
+
+/// Per-image entry of `scoring.json`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImageScore {
+    pub image: String,
+    pub matched: usize,
+    #[serde(default)]
+    pub matched_uids: Vec<String>,
+    pub missed: Vec<String>,
+    pub false_positives: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mean_corner_error_px: Option<f64>,
+}
+
+/// Per-family totals of `scoring.json`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct FamilyScore {
+    pub family: String,
+    pub ground_truth: usize,
+    pub matched: usize,
+    pub false_positives: usize,
+    /// Absent when the family has no detections.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub precision: Option<f64>,
+    /// Absent when the family has no expected tags.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recall: Option<f64>,
+}
+
+/// Family part of a `<family>:<id>` uid.
+fn family_of(uid: &str) -> &str {
+    uid.split_once(':').map_or(uid, |(family, _)| family)
+}
+
+/// Matched over detected, or `None` without detections.
+fn precision(matched: usize, false_positives: usize) -> Option<f64> {
+    (matched + false_positives > 0).then(|| matched as f64 / (matched + false_positives) as f64)
+}
+
+/// Matched over expected, or `None` without expected tags.
+fn recall(matched: usize, ground_truth: usize) -> Option<f64> {
+    (ground_truth > 0).then(|| matched as f64 / ground_truth as f64)
+}
+
+/// Run-level comparison against `--ground-truth`, written to `scoring.json`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Scoring {
+    pub total_ground_truth: usize,
+    pub total_matched: usize,
+    pub total_missed: usize,
+    pub total_false_positives: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub precision: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recall: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mean_corner_error_px: Option<f64>,
+    #[serde(default)]
+    pub families: Vec<FamilyScore>,
+    /// Images that had no ground-truth file and so were not scored.
+    #[serde(default)]
+    pub missing_ground_truth: Vec<String>,
+    pub images: Vec<ImageScore>,
+}
+
+impl Scoring {
+    pub fn from_images(images: Vec<ImageScore>, missing_ground_truth: Vec<String>) -> Self {
+        let total_matched: usize = images.iter().map(|i| i.matched).sum();
+        let total_missed: usize = images.iter().map(|i| i.missed.len()).sum();
+        let total_false_positives: usize = images.iter().map(|i| i.false_positives.len()).sum();
+        let error_sum: f64 = images
+            .iter()
+            .filter_map(|i| i.mean_corner_error_px.map(|e| e * i.matched as f64))
+            .sum();
+
+        let mut families: BTreeMap<&str, FamilyScore> = BTreeMap::new();
+        for image in &images {
+            for uid in &image.matched_uids {
+                let score = families.entry(family_of(uid)).or_default();
+                score.matched += 1;
+                score.ground_truth += 1;
+            }
+            for uid in &image.missed {
+                families.entry(family_of(uid)).or_default().ground_truth += 1;
+            }
+            for uid in &image.false_positives {
+                families.entry(family_of(uid)).or_default().false_positives += 1;
+            }
+        }
+        let families = families
+            .into_iter()
+            .map(|(family, score)| FamilyScore {
+                family: family.to_string(),
+                precision: precision(score.matched, score.false_positives),
+                recall: recall(score.matched, score.ground_truth),
+                ..score
+            })
+            .collect();
+
+        Scoring {
+            total_ground_truth: total_matched + total_missed,
+            total_matched,
+            total_missed,
+            total_false_positives,
+            precision: precision(total_matched, total_false_positives),
+            recall: recall(total_matched, total_matched + total_missed),
+            mean_corner_error_px: (total_matched > 0).then(|| error_sum / total_matched as f64),
+            families,
+            missing_ground_truth,
+            images,
+        }
+    }
+}
+
+/// A decoded tag that was dropped by the harness-side [`DetectionFilter`](crate::options::DetectionFilter) or by `--dedupe`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RejectedDetection {
+    pub tag_id: u16,
+    pub tag_family: String,
+    pub corners: Vec<Corner>,
+    pub decision_margin: f32,
+    pub hamming: u8,
+    pub reason: String,
+}
+
+/// Detections of one image after re-encoding it at a JPEG quality level.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QualityResult {
+    pub quality: u8,
+    pub detection_count: usize,
+    /// Baseline tags (by `uid`) still detected at this quality.
+    pub retained: usize,
+}
+
+/// Per-image entry of `robustness.json`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImageRobustness {
+    pub image: String,
+    pub baseline_count: usize,
+    pub qualities: Vec<QualityResult>,
+}
+
+/// Run-level totals for one JPEG quality level.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QualitySummary {
+    pub quality: u8,
+    pub total_detections: usize,
+    pub total_retained: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recall: Option<f64>,
+}
+
+/// JPEG quality sweep results written to `robustness.json`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Robustness {
+    pub total_baseline: usize,
+    pub qualities: Vec<QualitySummary>,
+    pub images: Vec<ImageRobustness>,
+}
+
+impl Robustness {
+    pub fn from_images(qualities: &[u8], images: Vec<ImageRobustness>) -> Self {
+        let total_baseline = images.iter().map(|i| i.baseline_count).sum();
+        let qualities = qualities
+            .iter()
+            .map(|&quality| {
+                let results = images
+                    .iter()
+                    .flat_map(|i| i.qualities.iter().filter(|q| q.quality == quality));
+                let (total_detections, total_retained) = results
+                    .fold((0, 0), |(d, r), q| (d + q.detection_count, r + q.retained));
+                QualitySummary {
+                    quality,
+                    total_detections,
+                    total_retained,
+                    recall: (total_baseline > 0).then(|| total_retained as f64 / total_baseline as f64),
+                }
+            })
+            .collect();
+
+        Robustness {
+            total_baseline,
+            qualities,
+            images,
+        }
+    }
+}
+
+/// An image that was skipped without producing output.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImageError {
+    pub image: String,
+    pub reason: String,
+    pub message: String,
+}
+
+/// Images skipped during a run, written to `errors.json`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorLog {
+    pub errors: Vec<ImageError>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RejectedResult {
+    pub image: String,
+    pub rejected: Vec<RejectedDetection>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    #[serde(default)]
+    pub schema_version: u32,
+    #[serde(default)]
+    pub detector: String,
+    pub supported_families: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub family_directories: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peak_memory_mb: Option<f64>,
+    #[serde(default)]
+    pub compressed: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auto_selected_families: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub partition_counts: Option<PartitionCounts>,
+    /// Whether `results.json` holds every result of the run (`--combined`).
+    #[serde(default)]
+    pub combined: bool,
+    /// Whether results are nested in subdirectories mirroring the input (`--recursive`).
+    #[serde(default)]
+    pub recursive: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decoder_config: Option<DecoderConfig>,
+    /// Whether only `counts.json` was written, without corner data (`--counts-only`).
+    #[serde(default)]
+    pub counts_only: bool,
+    /// Code properties of each family in `supported_families`.
+    #[serde(default)]
+    pub families: Vec<FamilyInfo>,
+    /// [`GrayMethod`](crate::color::GrayMethod) name of the RGB-to-gray
+    /// conversion (`--gray-method`); empty in older manifests.
+    #[serde(default)]
+    pub gray_method: String,
+}
+
+/// Properties of a tag family's code, as used by the decoder.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FamilyInfo {
+    pub name: String,
+    /// Data bits per tag.
+    pub bits: usize,
+    /// Minimum Hamming distance between any two codes, from the published
+    /// family definitions or the `--custom-family` file.
+    pub min_hamming: u32,
+    /// Number of distinct tag ids.
+    pub codes: usize,
+}
+
+/// Decoder settings in effect for the run, after any command-line overrides.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DecoderConfig {
+    pub quad_decimate: usize,
+    pub decode_sharpening: f32,
+    pub refine_edges: bool,
+}
+
+/// Result files written to each `--partition-output` subdirectory.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PartitionCounts {
+    pub detected: usize,
+    pub empty: usize,
+}
+
+/// Layout of the detection output (`--format`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// One `<image-basename>.json` per image.
+    Json,
+    /// A single `detections.json` array with one flat object per detection.
+    FlatJson,
+    /// A single `detections.csv` with one row per detection.
+    Csv,
+    /// One `<image-basename>.yaml` per image, with the fields of the JSON output.
+    Yaml,
+}
+
+impl OutputFormat {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "json" => Ok(OutputFormat::Json),
+            "flat-json" => Ok(OutputFormat::FlatJson),
+            "csv" => Ok(OutputFormat::Csv),
+            "yaml" => Ok(OutputFormat::Yaml),
+            _ => anyhow::bail!("Unknown --format: {} (expected json, flat-json, csv or yaml)", value),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputFormat::Json => "json",
+            OutputFormat::FlatJson => "flat-json",
+            OutputFormat::Csv => "csv",
+            OutputFormat::Yaml => "yaml",
+        }
+    }
+}
+
+/// Coordinate system of the written corners (`--coordinate-origin`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoordinateOrigin {
+    /// Pixel coordinates: origin at the top-left corner, y pointing down.
+    TopLeft,
+    /// Origin at the image centre, y pointing up.
+    Center,
+}
+
+impl CoordinateOrigin {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "topleft" => Ok(CoordinateOrigin::TopLeft),
+            "center" => Ok(CoordinateOrigin::Center),
+            _ => anyhow::bail!("Unknown --coordinate-origin: {} (expected topleft or center)", value),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CoordinateOrigin::TopLeft => "topleft",
+            CoordinateOrigin::Center => "center",
+        }
+    }
+}
+
+/// Maps a pixel position to centre-origin coordinates with y pointing up.
+pub fn centre_origin(corner: &Corner, width: f32, height: f32) -> Corner {
+    Corner {
+        x: corner.x - width / 2.0,
+        y: height / 2.0 - corner.y,
+    }
+}
+
+/// One detection in `--format flat-json` output, with the corners as
+/// top-level keys so it loads straight into a data frame.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FlatDetection {
+    pub image: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rotation_deg: Option<f32>,
+    pub family: String,
+    pub tag_id: u16,
+    pub x0: f32,
+    pub y0: f32,
+    pub x1: f32,
+    pub y1: f32,
+    pub x2: f32,
+    pub y2: f32,
+    pub x3: f32,
+    pub y3: f32,
+}
+
+impl FlatDetection {
+    pub fn new(image: &str, rotation_deg: Option<f32>, detection: &Detection) -> Self {
+        let c = &detection.corners;
+        FlatDetection {
+            image: image.to_string(),
+            rotation_deg,
+            family: detection.tag_family.clone(),
+            tag_id: detection.tag_id,
+            x0: c[0].x,
+            y0: c[0].y,
+            x1: c[1].x,
+            y1: c[1].y,
+            x2: c[2].x,
+            y2: c[2].y,
+            x3: c[3].x,
+            y3: c[3].y,
+        }
+    }
+}
+
+/// Number of detections of one family in one image, written to `counts.json`
+/// by `--counts-only`. Families that found nothing are listed with a count of 0.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DetectionCount {
+    pub image: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rotation_deg: Option<f32>,
+    pub family: String,
+    pub count: usize,
+}
+
+/// Stable key for a detection, used as `Detection::uid`.
+pub fn detection_uid(tag_family: &str, tag_id: u16) -> String {
+    format!("{}:{}", tag_family, tag_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn corner(x: f32, y: f32) -> Corner {
+        Corner { x, y }
+    }
+
+    fn detection(tag_family: &str, tag_id: u16, x: f32, y: f32) -> Detection {
+        Detection {
+            uid: detection_uid(tag_family, tag_id),
+            tag_id,
+            tag_family: tag_family.to_string(),
+            corners: Vec::new(),
+            center: corner(x, y),
+            decision_margin: 0.0,
+            hamming: 0,
+            area: 0.0,
+            aspect_ratio: 0.0,
+            winding: String::new(),
+            bbox: None,
+            distance_m: None,
+            scale: None,
+            refine_delta: None,
+            homography: None,
+            translation: None,
+            rotation: None,
+        }
+    }
+
+    fn keys(detections: &[Detection]) -> Vec<(String, u16, f32, f32)> {
+        detections
+            .iter()
+            .map(|d| (d.tag_family.clone(), d.tag_id, d.center.x, d.center.y))
+            .collect()
+    }
+
+    #[test]
+    fn sorted_detections_do_not_depend_on_input_order() {
+        let detections = vec![
+            detection("tag36h11", 7, 10.0, 50.0),
+            detection("tag16h5", 3, 0.0, 0.0),
+            detection("tag36h11", 2, 90.0, 10.0),
+            detection("tag36h11", 7, 40.0, 20.0),
+            detection("tag36h11", 7, 20.0, 20.0),
+        ];
+        let mut expected = detections.clone();
+        sort_detections(&mut expected);
+        assert_eq!(
+            keys(&expected),
+            vec![
+                ("tag16h5".to_string(), 3, 0.0, 0.0),
+                ("tag36h11".to_string(), 2, 90.0, 10.0),
+                ("tag36h11".to_string(), 7, 20.0, 20.0),
+                ("tag36h11".to_string(), 7, 40.0, 20.0),
+                ("tag36h11".to_string(), 7, 10.0, 50.0),
+            ]
+        );
+
+        for shift in 0..detections.len() {
+            for reverse in [false, true] {
+                let mut shuffled = detections.clone();
+                shuffled.rotate_left(shift);
+                if reverse {
+                    shuffled.reverse();
+                }
+                sort_detections(&mut shuffled);
+                assert_eq!(keys(&shuffled), keys(&expected));
+            }
+        }
+    }
+
+    #[test]
+    fn sorted_corners_start_bottom_left_and_run_counter_clockwise() {
+        // Clockwise on screen, starting from the top-right
+        let mut corners = vec![corner(10.0, 0.0), corner(10.0, 10.0), corner(0.0, 10.0), corner(0.0, 0.0)];
+        sort_corners_ccw(&mut corners);
+        let positions: Vec<(f32, f32)> = corners.iter().map(|c| (c.x, c.y)).collect();
+        assert_eq!(positions, vec![(0.0, 10.0), (10.0, 10.0), (10.0, 0.0), (0.0, 0.0)]);
+    }
+
+    #[test]
+    fn clockwise_quad_is_rewound_keeping_its_first_corner() {
+        let mut corners = vec![corner(0.0, 0.0), corner(10.0, 0.0), corner(10.0, 10.0), corner(0.0, 10.0)];
+        assert_eq!(quad_winding(&corners), "cw");
+        assert_eq!(polygon_area(&corners), 100.0);
+
+        normalize_winding(&mut corners);
+        let positions: Vec<(f32, f32)> = corners.iter().map(|c| (c.x, c.y)).collect();
+        assert_eq!(positions, vec![(0.0, 0.0), (0.0, 10.0), (10.0, 10.0), (10.0, 0.0)]);
+        assert_eq!(quad_winding(&corners), "ccw");
+        assert_eq!(polygon_area(&corners), 100.0);
+
+        normalize_winding(&mut corners);
+        let unchanged: Vec<(f32, f32)> = corners.iter().map(|c| (c.x, c.y)).collect();
+        assert_eq!(unchanged, positions);
+    }
+
+    fn image_summary(image: &str, families: &[(&str, usize)], detection_ms: f64) -> ImageSummary {
+        let family_timings = families
+            .iter()
+            .map(|&(family, detection_count)| FamilyTiming {
+                family: family.to_string(),
+                initialization_ms: 1.0,
+                detection_ms,
+                detection_cpu_ms: detection_ms,
+                repeat_stats: None,
+                scale: None,
+                timed_out: false,
+                detection_count,
+            })
+            .collect();
+        ImageSummary {
+            image: image.to_string(),
+            rotation_deg: None,
+            detection_count: families.iter().map(|&(_, count)| count).sum(),
+            family_detections: families
+                .iter()
+                .map(|&(family, count)| (family.to_string(), count))
+                .collect(),
+            filtered_count: None,
+            timings: Timings {
+                image_load_ms: 2.0,
+                total_initialization_ms: families.len() as f64,
+                total_detection_ms: detection_ms * families.len() as f64,
+                family_timings,
+            },
+        }
+    }
+
+    #[test]
+    fn summary_totals_images_and_families() {
+        let images = vec![
+            image_summary("a.png", &[("tag16h5", 1), ("tag36h11", 2)], 10.0),
+            image_summary("b.png", &[("tag36h11", 3)], 5.0),
+            image_summary("c.png", &[("tag36h11", 0)], 4.0),
+        ];
+        let summary = Summary::from_images(images, 100.0);
+
+        assert_eq!(summary.total_images, 3);
+        assert_eq!(summary.total_detections, 6);
+        assert_eq!(summary.total_image_load_ms, 6.0);
+        assert_eq!(summary.total_initialization_ms, 4.0);
+        assert_eq!(summary.total_detection_ms, 29.0);
+        assert_eq!(summary.family_detections, BTreeMap::from([("tag16h5".to_string(), 1), ("tag36h11".to_string(), 5)]));
+        assert_eq!(summary.mean_detections_per_image, 2.0);
+        assert_eq!(summary.wall_clock_ms, 100.0);
+    }
+
+    #[test]
+    fn empty_summary_has_zero_mean() {
+        let summary = Summary::from_images(Vec::new(), 1.0);
+        assert_eq!(summary.total_images, 0);
+        assert_eq!(summary.mean_detections_per_image, 0.0);
+    }
+
+    #[test]
+    fn repeat_stats_take_the_median_of_odd_and_even_runs() {
+        let odd = RepeatStats::from_samples(&[3.0, 1.0, 2.0]);
+        assert_eq!(odd.runs, 3);
+        assert_eq!(odd.min_ms, 1.0);
+        assert_eq!(odd.median_ms, 2.0);
+        assert_eq!(odd.mean_ms, 2.0);
+
+        let even = RepeatStats::from_samples(&[4.0, 1.0, 3.0, 2.0]);
+        assert_eq!(even.runs, 4);
+        assert_eq!(even.min_ms, 1.0);
+        assert_eq!(even.median_ms, 2.5);
+        assert_eq!(even.mean_ms, 2.5);
+        assert!((even.stddev_ms - 1.25f64.sqrt()).abs() < 1e-12);
+    }
+}
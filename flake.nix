@@ -20,7 +20,14 @@
             pname = "${name}-detector";
             version = "1.0.0";
 
-            inherit src;
+            # The crate depends on ../kornia-rs-common, so build from the
+            # detectors directory with just the two crates in it
+            src = pkgs.lib.fileset.toSource {
+              root = ./detectors;
+              fileset = pkgs.lib.fileset.unions [ src ./detectors/kornia-rs-common ];
+            };
+            cargoRoot = baseNameOf src;
+            buildAndTestSubdir = baseNameOf src;
 
             cargoLock = {
               lockFile = "${src}/Cargo.lock";
@@ -274,6 +281,31 @@
           echo "All detectors completed!"
         '';
 
+        # Benchmark the kornia-rs detectors into one CSV. Each binary links a
+        # different kornia-rs revision, so they cannot share one process.
+        benchmark-kornia-detectors = pkgs.writeShellScriptBin "benchmark-kornia-detectors" ''
+          INPUT_DIR="''${1:-data}"
+          CSV_FILE="''${2:-benchmark.csv}"
+          WORK_DIR=$(mktemp -d)
+          trap 'rm -rf "$WORK_DIR"' EXIT
+
+          rm -f "$CSV_FILE"
+          echo "Benchmarking kornia-rs detectors on $INPUT_DIR"
+
+          ${kornia-rs-apriltag-detector}/bin/kornia-rs-apriltag-detector \
+            --input "$INPUT_DIR" --output "$WORK_DIR/kornia-rs-apriltag" \
+            --bench-only --benchmark-csv "$CSV_FILE"
+          ${kornia-rs-apriltag-linefit-detector}/bin/kornia-rs-apriltag-linefit-detector \
+            --input "$INPUT_DIR" --output "$WORK_DIR/kornia-rs-apriltag-linefit" \
+            --bench-only --benchmark-csv "$CSV_FILE"
+          ${kornia-rs-apriltag-centred-coordinates-detector}/bin/kornia-rs-apriltag-centred-coordinates-detector \
+            --input "$INPUT_DIR" --output "$WORK_DIR/kornia-rs-apriltag-centred-coordinates" \
+            --bench-only --benchmark-csv "$CSV_FILE"
+
+          echo ""
+          echo "Wrote $CSV_FILE"
+        '';
+
         # Ground truth editor
         edit-ground-truth = pkgs.writeShellScriptBin "edit-ground-truth" ''
           echo "Starting Ground Truth Editor..."
@@ -350,14 +382,21 @@
       in
       {
         packages = {
-          inherit strip-exif apriltag-3-4-5 apriltag-3-4-5-detector run-apriltag-3-4-5 edit-ground-truth apriltags-kaess-3aea96d apriltags-kaess-3aea96d-detector run-apriltags-kaess-3aea96d kornia-rs-apriltag-detector run-kornia-rs-apriltag kornia-rs-apriltag-linefit-detector run-kornia-rs-apriltag-linefit kornia-rs-apriltag-centred-coordinates-detector run-kornia-rs-apriltag-centred-coordinates run-all-detectors compare-detectors;
+          inherit strip-exif apriltag-3-4-5 apriltag-3-4-5-detector run-apriltag-3-4-5 edit-ground-truth apriltags-kaess-3aea96d apriltags-kaess-3aea96d-detector run-apriltags-kaess-3aea96d kornia-rs-apriltag-detector run-kornia-rs-apriltag kornia-rs-apriltag-linefit-detector run-kornia-rs-apriltag-linefit kornia-rs-apriltag-centred-coordinates-detector run-kornia-rs-apriltag-centred-coordinates run-all-detectors benchmark-kornia-detectors compare-detectors;
+        };
+
+        # The kornia-rs detectors share one main.rs built against three pinned
+        # kornia-rs revisions, so `nix flake check` builds all of them to catch
+        # API drift between the revisions
+        checks = {
+          inherit kornia-rs-apriltag-detector kornia-rs-apriltag-linefit-detector kornia-rs-apriltag-centred-coordinates-detector;
         };
 
         apps = pkgs.lib.mapAttrs (name: pkg: {
           type = "app";
           program = "${pkg}/bin/${name}";
         }) {
-          inherit strip-exif run-apriltag-3-4-5 run-apriltags-kaess-3aea96d run-kornia-rs-apriltag run-kornia-rs-apriltag-linefit run-kornia-rs-apriltag-centred-coordinates run-all-detectors edit-ground-truth compare-detectors;
+          inherit strip-exif run-apriltag-3-4-5 run-apriltags-kaess-3aea96d run-kornia-rs-apriltag run-kornia-rs-apriltag-linefit run-kornia-rs-apriltag-centred-coordinates run-all-detectors benchmark-kornia-detectors edit-ground-truth compare-detectors;
         };
 
         devShells.default = pkgs.mkShell {
@@ -379,6 +418,7 @@
             echo "  run-kornia-rs-apriltag-linefit - Run Kornia-rs AprilTag (linefit) detector on data/"
             echo "  run-kornia-rs-apriltag-centred-coordinates - Run Kornia-rs AprilTag (centred-coordinates) detector on data/"
             echo "  run-all-detectors             - Run all detectors in sequence"
+            echo "  benchmark-kornia-detectors    - Benchmark the kornia-rs detectors into one CSV"
             echo "  edit-ground-truth             - Open ground truth annotation tool"
             echo "  compare-detectors             - Generate comparison report vs ground truth"
           '';